@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use super::{codec::CodecError, resize::Resize};
+
+const DEFAULT_WIDTH: u32 = 800;
+const DEFAULT_MAX_FPS: u32 = 24;
+
+const MAX_WIDTH: u32 = 4096;
+const MAX_FPS: u32 = 50;
+
+/// Controls shared by every animated image output (gif, webp, apng and avif).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnimationOptions {
+    /// Width in pixels, keeping the aspect ratio. Defaults to 800.
+    pub width: Option<u32>,
+    /// Most frames per second to keep. Defaults to 24.
+    pub fps: Option<u32>,
+    /// How many extra times to play it: 0 loops forever (the default), -1 plays once.
+    #[serde(rename = "loop")]
+    pub loop_count: Option<i32>,
+}
+
+impl AnimationOptions {
+    pub fn validate(&self, resize: Option<&Resize>) -> Result<(), CodecError> {
+        if self
+            .width
+            .is_some_and(|width| !(2..=MAX_WIDTH).contains(&width))
+        {
+            return Err(CodecError::InvalidAnimation("width is out of range"));
+        }
+        if self.fps.is_some_and(|fps| !(1..=MAX_FPS).contains(&fps)) {
+            return Err(CodecError::InvalidAnimation("fps is out of range"));
+        }
+        if self.loop_count.is_some_and(|loop_count| loop_count < -1) {
+            return Err(CodecError::InvalidAnimation("loop can't be below -1"));
+        }
+        if resize.is_some() && self.width.is_some() {
+            return Err(CodecError::InvalidAnimation(
+                "width can't be combined with resize",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `AnimationOptions` with the defaults filled in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationSettings {
+    pub width: u32,
+    pub fps: u32,
+    pub loop_count: i32,
+}
+
+impl AnimationSettings {
+    /// There's no point making up frames, so the frame rate is capped at the source's.
+    pub fn new(options: Option<&AnimationOptions>, source_fps: u32) -> Self {
+        let options = options.cloned().unwrap_or_default();
+        let max_fps = options.fps.unwrap_or(DEFAULT_MAX_FPS);
+        Self {
+            width: options.width.unwrap_or(DEFAULT_WIDTH),
+            fps: if source_fps > 0 {
+                max_fps.min(source_fps)
+            } else {
+                max_fps
+            },
+            loop_count: options.loop_count.unwrap_or(0),
+        }
+    }
+
+    /// Drops the frame rate and scales, rounding to even dimensions for the formats
+    /// that need them. A resize takes the place of the width.
+    pub fn filter(&self, resize: Option<&Resize>) -> String {
+        let scale = resize.map_or_else(
+            || format!("scale={}:-2:flags=lanczos", self.width.max(2) & !1),
+            |resize| resize.software_filter(),
+        );
+        format!("fps={},{}", self.fps, scale)
+    }
+
+    /// Total number of plays, with 0 meaning forever. This is how webp, apng and avif
+    /// count, where gif counts the extra plays instead.
+    pub fn plays(&self) -> u32 {
+        match self.loop_count {
+            0 => 0,
+            -1 => 1,
+            extra => extra as u32 + 1,
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+use super::{
+    animation::AnimationOptions, format::ConverterFormat, gif::GifOptions, resize::Resize,
+};
+
+/// Named the way ffmpeg names them, so probed codecs parse straight into these.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum VideoCodec {
+    H264,
+    HEVC,
+    AV1,
+    VP9,
+}
+
+impl VideoCodec {
+    /// Prefix of the hardware encoders for this codec, e.g. "hevc" for hevc_nvenc.
+    pub fn hardware_name(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "h264",
+            VideoCodec::HEVC => "hevc",
+            VideoCodec::AV1 => "av1",
+            VideoCodec::VP9 => "vp9",
+        }
+    }
+
+    /// Encoder to fall back to when there's no hardware encoder for this codec.
+    pub fn software_encoder(&self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::HEVC => "libx265",
+            VideoCodec::AV1 => "libsvtav1",
+            VideoCodec::VP9 => "libvpx-vp9",
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Display, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AudioCodec {
+    AAC,
+    Opus,
+    MP3,
+    Vorbis,
+    FLAC,
+}
+
+impl AudioCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::AAC => "aac",
+            AudioCodec::Opus => "libopus",
+            AudioCodec::MP3 => "libmp3lame",
+            AudioCodec::Vorbis => "libvorbis",
+            AudioCodec::FLAC => "flac",
+        }
+    }
+}
+
+/// How the video encoder decides how many bits to spend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateControl {
+    /// Aim for a bitrate based on the source's.
+    #[default]
+    Bitrate,
+    /// Aim for a constant quality and let the size fall where it may.
+    Quality,
+    /// Aim for a file size, given by `ConversionOptions::target_size`.
+    Size,
+}
+
+const DEFAULT_QUALITY: u8 = 70;
+
+/// Audio bitrate used when targeting a size, so the video knows how much room it has left.
+pub const TARGET_SIZE_AUDIO_BITRATE: u64 = 128_000;
+/// Below this the video is just mush, so we'd rather fail than produce it.
+const MIN_TARGET_VIDEO_BITRATE: u64 = 50_000;
+
+/// Video bitrate that should land a `duration` second long output at about `target_size`
+/// bytes, with room for the audio if there is any. `None` if the target is too small to
+/// fit a watchable video.
+pub fn target_video_bitrate(target_size: u64, duration: f64, has_audio: bool) -> Option<u64> {
+    // leave a little room for the container's own overhead
+    let total = target_size as f64 * 8.0 * 0.97 / duration;
+    let audio = if has_audio {
+        TARGET_SIZE_AUDIO_BITRATE
+    } else {
+        0
+    };
+    let video = total - audio as f64;
+    (video >= MIN_TARGET_VIDEO_BITRATE as f64).then_some(video as u64)
+}
+
+/// Whether `encoder` can do a two-pass encode, which is much better at hitting a size.
+pub fn supports_two_pass(encoder: &str) -> bool {
+    matches!(
+        encoder,
+        "libx264" | "libx265" | "libvpx" | "libvpx-vp9" | "mpeg4"
+    )
+}
+
+/// Args for one pass of a two-pass encode. The passes share their stats through files
+/// starting with `log_prefix`.
+pub fn pass_args(encoder: &str, pass: usize, log_prefix: &str) -> Vec<String> {
+    match encoder {
+        // x265 ignores -pass and wants it through its own params instead
+        "libx265" => vec![
+            "-x265-params".to_string(),
+            format!("pass={}:stats={}.log", pass, log_prefix),
+        ],
+        _ => vec![
+            "-pass".to_string(),
+            pass.to_string(),
+            "-passlogfile".to_string(),
+            log_prefix.to_string(),
+        ],
+    }
+}
+
+/// Quality-targeting args for `encoder`, with `quality` going from 0 (worst) to 100 (best).
+/// `None` if the encoder doesn't have a constant quality mode we know of.
+pub fn quality_args(encoder: &str, quality: u8, bitrate: u64) -> Option<Vec<String>> {
+    // maps the slider onto the encoder's own scale, where lower is usually better
+    let scale = |worst: f64, best: f64| {
+        (worst + (best - worst) * quality as f64 / 100.0)
+            .round()
+            .to_string()
+    };
+
+    let args = match encoder {
+        "libx264" => vec!["-crf".to_string(), scale(38.0, 16.0)],
+        "libx265" => vec!["-crf".to_string(), scale(40.0, 18.0)],
+        "libsvtav1" => vec!["-crf".to_string(), scale(55.0, 20.0)],
+        // like vp9, libaom only goes for constant quality with -b:v 0
+        "libaom-av1" => vec![
+            "-crf".to_string(),
+            scale(55.0, 20.0),
+            "-b:v".to_string(),
+            "0".to_string(),
+        ],
+        // -b:v 0 is what switches vp9 into constant quality
+        "libvpx-vp9" => vec![
+            "-crf".to_string(),
+            scale(50.0, 15.0),
+            "-b:v".to_string(),
+            "0".to_string(),
+        ],
+        // vp8 only does constrained quality, where the bitrate acts as a ceiling
+        "libvpx" => vec![
+            "-crf".to_string(),
+            scale(50.0, 10.0),
+            "-b:v".to_string(),
+            bitrate.to_string(),
+        ],
+        "mpeg4" | "wmv2" => vec!["-q:v".to_string(), scale(31.0, 2.0)],
+        _ if encoder.ends_with("_nvenc") => vec![
+            "-rc".to_string(),
+            "vbr".to_string(),
+            "-cq".to_string(),
+            scale(40.0, 18.0),
+            "-b:v".to_string(),
+            "0".to_string(),
+        ],
+        _ if encoder.ends_with("_vaapi") => vec![
+            "-rc_mode".to_string(),
+            "CQP".to_string(),
+            "-qp".to_string(),
+            scale(40.0, 18.0),
+        ],
+        _ if encoder.ends_with("_qsv") => vec!["-global_quality".to_string(), scale(40.0, 18.0)],
+        _ if encoder.ends_with("_amf") => vec![
+            "-rc".to_string(),
+            "cqp".to_string(),
+            "-qp_i".to_string(),
+            scale(40.0, 18.0),
+            "-qp_p".to_string(),
+            scale(40.0, 18.0),
+        ],
+        // videotoolbox already goes from 1 to 100, higher is better
+        _ if encoder.ends_with("_videotoolbox") => {
+            vec!["-q:v".to_string(), quality.max(1).to_string()]
+        }
+        _ => return None,
+    };
+
+    Some(args)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("{codec} video can't be stored in .{format}")]
+    UnsupportedVideo {
+        codec: VideoCodec,
+        format: ConverterFormat,
+    },
+    #[error("{codec} audio can't be stored in .{format}")]
+    UnsupportedAudio {
+        codec: AudioCodec,
+        format: ConverterFormat,
+    },
+    #[error("quality must be between 0 and 100")]
+    InvalidQuality,
+    #[error("a target size is needed to target a size")]
+    MissingTargetSize,
+    #[error(".{0} can't target a size")]
+    SizeUnsupported(ConverterFormat),
+    #[error("invalid clip: {0}")]
+    InvalidClip(&'static str),
+    #[error("invalid resize")]
+    InvalidResize,
+    #[error("invalid animation options: {0}")]
+    InvalidAnimation(&'static str),
+    #[error("gif options only apply to .gif, not .{0}")]
+    GifOptionsUnsupported(ConverterFormat),
+    #[error("animation options only apply to .webp, .apng and .avif, not .{0}")]
+    AnimationOptionsUnsupported(ConverterFormat),
+    #[error(".{0} has no video to resize")]
+    ResizeAudio(ConverterFormat),
+}
+
+/// Optional tweaks to a conversion. Anything left out falls back to the format's defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionOptions {
+    pub video_codec: Option<VideoCodec>,
+    pub audio_codec: Option<AudioCodec>,
+    #[serde(default)]
+    pub rate_control: RateControl,
+    /// 0 (worst) to 100 (best), only used when targeting quality.
+    pub quality: Option<u8>,
+    /// In bytes, only used when targeting a size.
+    pub target_size: Option<u64>,
+    /// Re-encode even when the streams could just be copied into the new container.
+    #[serde(default)]
+    pub reencode: bool,
+    /// Where the clip starts, in seconds.
+    pub start: Option<f64>,
+    /// Where the clip ends, in seconds. Mutually exclusive with `duration`.
+    pub end: Option<f64>,
+    /// How long the clip is, in seconds.
+    pub duration: Option<f64>,
+    pub resize: Option<Resize>,
+    /// Only for gif outputs.
+    pub gif: Option<GifOptions>,
+    /// Only for the other animated image outputs, gifs take theirs through `gif`.
+    pub animation: Option<AnimationOptions>,
+}
+
+impl ConversionOptions {
+    pub fn quality(&self) -> u8 {
+        self.quality.unwrap_or(DEFAULT_QUALITY)
+    }
+
+    /// The byte limit of a size-bounded gif.
+    pub fn gif_max_size(&self) -> Option<u64> {
+        self.gif.as_ref().and_then(|gif| gif.max_size)
+    }
+
+    /// Whether only part of the input gets converted.
+    pub fn is_clipped(&self) -> bool {
+        self.start.is_some() || self.end.is_some() || self.duration.is_some()
+    }
+
+    /// How much of an input `length` seconds long ends up in the output.
+    pub fn clipped_length(&self, length: f64) -> f64 {
+        let start = self.start.unwrap_or(0.0).min(length);
+        let end = match (self.end, self.duration) {
+            (Some(end), _) => end,
+            (None, Some(duration)) => start + duration,
+            (None, None) => length,
+        };
+        (end.min(length) - start).max(0.0)
+    }
+
+    /// Seeks to the start of the clip. These go before the input, where ffmpeg seeks
+    /// quickly and still lands on the exact frame when re-encoding.
+    pub fn seek_args(&self) -> Vec<String> {
+        match self.start {
+            Some(start) if start > 0.0 => vec!["-ss".to_string(), start.to_string()],
+            _ => vec![],
+        }
+    }
+
+    /// Stops the output at the end of the clip. Goes after the input.
+    pub fn limit_args(&self) -> Vec<String> {
+        let length = match (self.end, self.duration) {
+            (Some(end), _) => end - self.start.unwrap_or(0.0),
+            (None, Some(duration)) => duration,
+            (None, None) => return vec![],
+        };
+        vec!["-t".to_string(), length.to_string()]
+    }
+
+    /// Checks that the requested codecs can actually be stored in the output container
+    /// and that the rest of the options are in range.
+    pub fn validate(&self, to: &ConverterFormat) -> Result<(), CodecError> {
+        if let Some(codec) = self.video_codec {
+            if !to.video_codecs().contains(&codec) {
+                return Err(CodecError::UnsupportedVideo { codec, format: *to });
+            }
+        }
+
+        if let Some(codec) = self.audio_codec {
+            if !to.audio_codecs().contains(&codec) {
+                return Err(CodecError::UnsupportedAudio { codec, format: *to });
+            }
+        }
+
+        if self.quality.is_some_and(|quality| quality > 100) {
+            return Err(CodecError::InvalidQuality);
+        }
+
+        if self.start.is_some_and(|start| start < 0.0) {
+            return Err(CodecError::InvalidClip("start can't be negative"));
+        }
+        match (self.end, self.duration) {
+            (Some(_), Some(_)) => {
+                return Err(CodecError::InvalidClip(
+                    "give either an end or a duration, not both",
+                ))
+            }
+            (Some(end), None) if end <= self.start.unwrap_or(0.0) => {
+                return Err(CodecError::InvalidClip(
+                    "the clip has to end after it starts",
+                ))
+            }
+            (None, Some(duration)) if duration <= 0.0 => {
+                return Err(CodecError::InvalidClip("duration has to be positive"))
+            }
+            _ => {}
+        }
+
+        if let Some(resize) = &self.resize {
+            if to.is_audio() {
+                return Err(CodecError::ResizeAudio(*to));
+            }
+            resize.validate()?;
+        }
+
+        if let Some(gif) = &self.gif {
+            if *to != ConverterFormat::GIF {
+                return Err(CodecError::GifOptionsUnsupported(*to));
+            }
+            gif.validate(self.resize.as_ref())?;
+        }
+
+        if let Some(animation) = &self.animation {
+            if !to.is_animation() || *to == ConverterFormat::GIF {
+                return Err(CodecError::AnimationOptionsUnsupported(*to));
+            }
+            animation.validate(self.resize.as_ref())?;
+        }
+
+        if self.rate_control == RateControl::Size {
+            if !to.can_target_size() {
+                return Err(CodecError::SizeUnsupported(*to));
+            }
+            if self.target_size.is_none_or(|size| size == 0) {
+                return Err(CodecError::MissingTargetSize);
+            }
+        }
+
+        Ok(())
+    }
+}
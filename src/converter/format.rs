@@ -1,7 +1,15 @@
-use super::{gpu::ConverterGPU, speed::ConversionSpeed};
+use super::{
+    animation::AnimationSettings,
+    codec::{self, AudioCodec, ConversionOptions, RateControl, VideoCodec},
+    gif::GifSettings,
+    gpu::ConverterGPU,
+    job::{Job, ProbedStream},
+    speed::ConversionSpeed,
+};
 use log::info;
 use strum_macros::{Display, EnumString};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq, EnumString, Display)]
 #[strum(serialize_all = "lowercase")]
 pub enum ConverterFormat {
@@ -15,27 +23,175 @@ pub enum ConverterFormat {
     MTS,
     TS,
     M2TS,
+    MP3,
+    AAC,
+    M4A,
+    Opus,
+    OGG,
+    FLAC,
+    WAV,
+    WebP,
+    APNG,
+    AVIF,
 }
 
 impl ConverterFormat {
+    /// Whether this is an audio-only format.
+    pub fn is_audio(&self) -> bool {
+        matches!(
+            self,
+            ConverterFormat::MP3
+                | ConverterFormat::AAC
+                | ConverterFormat::M4A
+                | ConverterFormat::Opus
+                | ConverterFormat::OGG
+                | ConverterFormat::FLAC
+                | ConverterFormat::WAV
+        )
+    }
+
+    /// Whether this is an animated image format.
+    pub fn is_animation(&self) -> bool {
+        matches!(
+            self,
+            ConverterFormat::GIF
+                | ConverterFormat::WebP
+                | ConverterFormat::APNG
+                | ConverterFormat::AVIF
+        )
+    }
+
+    /// Formats that are only ever written. Most ffmpeg builds can't decode animated webp,
+    /// so uploads of these aren't taken.
+    pub fn is_output_only(&self) -> bool {
+        matches!(
+            self,
+            ConverterFormat::WebP | ConverterFormat::APNG | ConverterFormat::AVIF
+        )
+    }
+
+    /// Whether it can be encoded to land at a given size. Audio and animations aren't
+    /// encoded at a bitrate that could be aimed with.
+    pub fn can_target_size(&self) -> bool {
+        !self.is_audio() && !self.is_animation()
+    }
+
+    /// Video codecs that can be asked for when converting to this format.
+    pub fn video_codecs(&self) -> &'static [VideoCodec] {
+        match self {
+            ConverterFormat::MP4 | ConverterFormat::MOV => {
+                &[VideoCodec::H264, VideoCodec::HEVC, VideoCodec::AV1]
+            }
+            ConverterFormat::MKV => &[
+                VideoCodec::H264,
+                VideoCodec::HEVC,
+                VideoCodec::AV1,
+                VideoCodec::VP9,
+            ],
+            ConverterFormat::WebM => &[VideoCodec::AV1, VideoCodec::VP9],
+            ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => {
+                &[VideoCodec::H264, VideoCodec::HEVC]
+            }
+            _ => &[],
+        }
+    }
+
+    /// Audio codecs that can be asked for when converting to this format.
+    pub fn audio_codecs(&self) -> &'static [AudioCodec] {
+        match self {
+            ConverterFormat::MP4 => &[AudioCodec::AAC, AudioCodec::MP3, AudioCodec::Opus],
+            // the mov muxer has no tag for opus
+            ConverterFormat::MOV => &[AudioCodec::AAC, AudioCodec::MP3],
+            ConverterFormat::MKV => &[
+                AudioCodec::AAC,
+                AudioCodec::Opus,
+                AudioCodec::MP3,
+                AudioCodec::Vorbis,
+                AudioCodec::FLAC,
+            ],
+            ConverterFormat::WebM => &[AudioCodec::Opus, AudioCodec::Vorbis],
+            ConverterFormat::MTS | ConverterFormat::TS | ConverterFormat::M2TS => {
+                &[AudioCodec::AAC, AudioCodec::MP3]
+            }
+            ConverterFormat::MP3 => &[AudioCodec::MP3],
+            ConverterFormat::AAC | ConverterFormat::M4A => &[AudioCodec::AAC],
+            ConverterFormat::Opus => &[AudioCodec::Opus],
+            ConverterFormat::OGG => &[AudioCodec::Vorbis, AudioCodec::Opus, AudioCodec::FLAC],
+            ConverterFormat::FLAC => &[AudioCodec::FLAC],
+            _ => &[],
+        }
+    }
+
     pub fn conversion_into_args(
         &self,
         speed: &ConversionSpeed,
         gpu: &ConverterGPU,
         bitrate: u64,
+        encoder: Option<&str>,
+        options: &ConversionOptions,
     ) -> Vec<String> {
-        speed.to_args(self, gpu, bitrate)
+        speed.to_args(self, gpu, bitrate, encoder, options)
+    }
+}
+
+/// What a conversion reads from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// The uploaded file, in this format.
+    File(ConverterFormat),
+    /// Numbered images, played back at `fps`.
+    ImageSequence { fps: u32 },
+}
+
+impl Source {
+    /// Everything ffmpeg needs to open the job's input, `-i` included.
+    pub fn input_args(&self, job: &Job) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Source::ImageSequence { fps } = self {
+            args.extend(["-framerate".to_string(), fps.to_string()]);
+        }
+        args.extend(["-i".to_string(), job.input_path()]);
+        args
     }
 }
 
 pub struct Conversion {
-    pub from: ConverterFormat,
+    pub source: Source,
     pub to: ConverterFormat,
+    pub options: ConversionOptions,
 }
 
 impl Conversion {
-    pub fn new(from: ConverterFormat, to: ConverterFormat) -> Self {
-        Self { from, to }
+    pub fn new(source: Source, to: ConverterFormat, options: ConversionOptions) -> Self {
+        Self {
+            source,
+            to,
+            options,
+        }
+    }
+
+    /// The requested video encoder if one was asked for, otherwise the format's default.
+    async fn video_encoder(&self, gpu: &ConverterGPU, codecs: &[&str], default: &str) -> String {
+        match self.options.video_codec {
+            Some(codec) => {
+                self.accelerated_or_default_codec(
+                    gpu,
+                    &[codec.hardware_name()],
+                    codec.software_encoder(),
+                )
+                .await
+            }
+            None => {
+                self.accelerated_or_default_codec(gpu, codecs, default)
+                    .await
+            }
+        }
+    }
+
+    fn audio_encoder<'a>(&self, default: &'a str) -> &'a str {
+        self.options
+            .audio_codec
+            .map_or(default, |codec| codec.encoder())
     }
 
     async fn accelerated_or_default_codec(
@@ -52,6 +208,69 @@ impl Conversion {
         default.to_string()
     }
 
+    /// Args for copying the input's streams into the output as they are, if the output
+    /// can hold all of them and nothing asked for a re-encode.
+    pub fn remux_args(&self, streams: &[ProbedStream]) -> Option<Vec<String>> {
+        let options = &self.options;
+        // still images always have to be encoded into a video
+        if matches!(self.source, Source::ImageSequence { .. }) {
+            return None;
+        }
+        // copies can only be cut on keyframes, so clips get re-encoded to cut accurately
+        if options.reencode
+            || options.is_clipped()
+            || options.resize.is_some()
+            || options.rate_control != RateControl::Bitrate
+        {
+            return None;
+        }
+
+        let video_fits = |codec: &str| {
+            codec.parse::<VideoCodec>().ok().filter(|codec| {
+                self.to.video_codecs().contains(codec)
+                    && options.video_codec.is_none_or(|wanted| wanted == *codec)
+            })
+        };
+        let audio_fits = |codec: &str| {
+            codec.parse::<AudioCodec>().is_ok_and(|codec| {
+                self.to.audio_codecs().contains(&codec)
+                    && options.audio_codec.is_none_or(|wanted| wanted == codec)
+            })
+        };
+
+        let mut video = None;
+        let mut has_audio = false;
+        for stream in streams {
+            match stream.kind.as_str() {
+                // audio outputs drop the video anyway
+                "video" if self.to.is_audio() => {}
+                "video" if !stream.attached_pic => video = Some(video_fits(&stream.codec)?),
+                "audio" if audio_fits(&stream.codec) => has_audio = true,
+                // subtitles, cover art and so on would need converting too
+                _ => return None,
+            }
+        }
+
+        let mut args = if self.to.is_audio() {
+            if !has_audio {
+                return None;
+            }
+            vec!["-vn".to_string(), "-c:a".to_string(), "copy".to_string()]
+        } else {
+            video?;
+            vec!["-c".to_string(), "copy".to_string()]
+        };
+
+        // same as when encoding, apple players want hevc tagged as hvc1
+        if video == Some(VideoCodec::HEVC)
+            && matches!(self.to, ConverterFormat::MP4 | ConverterFormat::MOV)
+        {
+            args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+        }
+
+        Some(args)
+    }
+
     pub async fn to_args(
         &self,
         speed: &ConversionSpeed,
@@ -59,6 +278,8 @@ impl Conversion {
         bitrate: u64,
         fps: u32,
     ) -> anyhow::Result<Vec<String>> {
+        // the speed settings depend on which encoder ends up being used
+        let mut video_encoder: Option<String> = None;
         let conversion_opts: Vec<String> = match self.to {
             ConverterFormat::MP4
             | ConverterFormat::MKV
@@ -66,19 +287,24 @@ impl Conversion {
             | ConverterFormat::MTS
             | ConverterFormat::TS
             | ConverterFormat::M2TS => {
-                let encoder = self
-                    .accelerated_or_default_codec(gpu, &["h264"], "libx264")
-                    .await;
+                let encoder = self.video_encoder(gpu, &["h264"], "libx264").await;
 
                 let mut opts = vec![
                     "-c:v".to_string(),
                     encoder.clone(), // Clone to check its name below
                     "-c:a".to_string(),
-                    "aac".to_string(),
+                    self.audio_encoder("aac").to_string(),
                     "-strict".to_string(),
                     "experimental".to_string(),
                 ];
 
+                // apple players only pick up hevc in mp4/mov when it's tagged as hvc1
+                if self.options.video_codec == Some(VideoCodec::HEVC)
+                    && matches!(self.to, ConverterFormat::MP4 | ConverterFormat::MOV)
+                {
+                    opts.extend(vec!["-tag:v".to_string(), "hvc1".to_string()]);
+                }
+
                 // UNIVERSAL FIX: If we're using any hardware encoder for these common containers,
                 // force the output pixel format to the universally compatible yuv420p.
                 // This prevents the green/pink hue artifact on all affected formats.
@@ -87,23 +313,62 @@ impl Conversion {
                     opts.extend(vec!["-pix_fmt".to_string(), "yuv420p".to_string()]);
                 }
 
+                video_encoder = Some(encoder);
                 opts
             }
 
             ConverterFormat::GIF => {
-                vec![
-                    "-filter_complex".to_string(),
-                    format!(
-                     "fps={},scale=800:-1:flags=lanczos,split[s0][s1];[s0]palettegen=max_colors=64[p];[s1][p]paletteuse=dither=bayer",
-                     fps.min(24)
-                    ),
-                ]
+                GifSettings::new(self.options.gif.as_ref(), fps).args(self.options.resize.as_ref())
+            }
+
+            ConverterFormat::WebP | ConverterFormat::APNG | ConverterFormat::AVIF => {
+                let animation = AnimationSettings::new(self.options.animation.as_ref(), fps);
+                let mut opts = vec![
+                    "-vf".to_string(),
+                    animation.filter(self.options.resize.as_ref()),
+                ];
+                match self.to {
+                    ConverterFormat::WebP => opts.extend([
+                        "-c:v".to_string(),
+                        "libwebp_anim".to_string(),
+                        "-quality".to_string(),
+                        self.options.quality().to_string(),
+                        "-loop".to_string(),
+                        animation.plays().to_string(),
+                    ]),
+                    ConverterFormat::APNG => opts.extend([
+                        "-c:v".to_string(),
+                        "apng".to_string(),
+                        "-plays".to_string(),
+                        animation.plays().to_string(),
+                    ]),
+                    _ => {
+                        // libaom handles the tiny frame sizes animations tend to have,
+                        // where svt-av1 has a minimum
+                        let encoder = "libaom-av1";
+                        opts.extend([
+                            "-c:v".to_string(),
+                            encoder.to_string(),
+                            "-pix_fmt".to_string(),
+                            "yuv420p".to_string(),
+                            "-loop".to_string(),
+                            animation.plays().to_string(),
+                        ]);
+                        opts.extend(
+                            codec::quality_args(encoder, self.options.quality(), 0)
+                                .unwrap_or_default(),
+                        );
+                        video_encoder = Some(encoder.to_string());
+                    }
+                }
+                opts
             }
 
             ConverterFormat::WMV => {
                 let encoder = self
                     .accelerated_or_default_codec(gpu, &["wmv2", "wmv3"], "wmv2")
                     .await;
+                video_encoder = Some(encoder.clone());
                 vec![
                     "-c:v".to_string(),
                     encoder,
@@ -113,19 +378,21 @@ impl Conversion {
             }
             ConverterFormat::WebM => {
                 let encoder = self
-                    .accelerated_or_default_codec(gpu, &["av1", "vp9", "vp8"], "libvpx")
+                    .video_encoder(gpu, &["av1", "vp9", "vp8"], "libvpx")
                     .await;
+                video_encoder = Some(encoder.clone());
                 vec![
                     "-c:v".to_string(),
                     encoder.to_string(),
                     "-c:a".to_string(),
-                    "libvorbis".to_string(),
+                    self.audio_encoder("libvorbis").to_string(),
                 ]
             }
             ConverterFormat::AVI => {
                 let encoder = self
                     .accelerated_or_default_codec(gpu, &["mpeg4"], "mpeg4")
                     .await;
+                video_encoder = Some(encoder.clone());
                 vec![
                     "-c:v".to_string(),
                     encoder,
@@ -133,6 +400,29 @@ impl Conversion {
                     "libmp3lame".to_string(),
                 ]
             }
+
+            // audio only -- drop the video (and cover art) and just encode the audio
+            ConverterFormat::MP3
+            | ConverterFormat::AAC
+            | ConverterFormat::M4A
+            | ConverterFormat::Opus
+            | ConverterFormat::OGG
+            | ConverterFormat::FLAC
+            | ConverterFormat::WAV => {
+                let codec = match self.to {
+                    ConverterFormat::MP3 => "libmp3lame",
+                    ConverterFormat::AAC | ConverterFormat::M4A => "aac",
+                    ConverterFormat::Opus => "libopus",
+                    ConverterFormat::OGG => "libvorbis",
+                    ConverterFormat::FLAC => "flac",
+                    _ => "pcm_s16le",
+                };
+                vec![
+                    "-vn".to_string(),
+                    "-c:a".to_string(),
+                    self.audio_encoder(codec).to_string(),
+                ]
+            }
         };
 
         let conversion_opts = conversion_opts
@@ -142,7 +432,13 @@ impl Conversion {
 
         let result = [
             conversion_opts,
-            self.to.conversion_into_args(speed, gpu, bitrate),
+            self.to.conversion_into_args(
+                speed,
+                gpu,
+                bitrate,
+                video_encoder.as_deref(),
+                &self.options,
+            ),
         ]
         .concat();
 
@@ -0,0 +1,231 @@
+use std::{fs::File, io, path::Path, time::Instant};
+
+use anyhow::anyhow;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::mpsc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use super::{
+    job::{Job, ProgressUpdate},
+    run_ffmpeg,
+    thumbnail::{self, ThumbnailFormat},
+    ProgressContext,
+};
+
+/// Anything past this is better off as a video.
+const MAX_FRAMES: u64 = 10_000;
+const MAX_FPS: f64 = 120.0;
+const MAX_WIDTH: u32 = 8192;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FramesError {
+    #[error("invalid frame export options: {0}")]
+    InvalidOptions(&'static str),
+    #[error("that would be about {0} frames, the most is {MAX_FRAMES}")]
+    TooManyFrames(u64),
+    #[error("couldn't work out how many frames the input has")]
+    UnknownFrameCount,
+}
+
+/// Which of the input's frames make it into the archive.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum FrameSelection {
+    #[default]
+    All,
+    /// Every `nth` frame, starting with the first.
+    Nth { nth: u32 },
+    /// Resampled to this many frames per second.
+    Fps { fps: f64 },
+}
+
+/// Dumps frames of the input as images, zipped up into a single download.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameExportOptions {
+    #[serde(default)]
+    pub select: FrameSelection,
+    #[serde(default)]
+    pub format: ThumbnailFormat,
+    /// See `thumbnail::scale_filter`.
+    pub width: Option<u32>,
+}
+
+impl FrameExportOptions {
+    pub fn validate(&self) -> Result<(), FramesError> {
+        match self.select {
+            FrameSelection::Nth { nth: 0 } => {
+                return Err(FramesError::InvalidOptions("nth has to be at least 1"))
+            }
+            FrameSelection::Fps { fps } if !(fps > 0.0 && fps <= MAX_FPS) => {
+                return Err(FramesError::InvalidOptions("fps is out of range"))
+            }
+            _ => {}
+        }
+        if self
+            .width
+            .is_some_and(|width| !(2..=MAX_WIDTH).contains(&width))
+        {
+            return Err(FramesError::InvalidOptions("width is out of range"));
+        }
+        Ok(())
+    }
+}
+
+/// Where the frames go before they're zipped up.
+fn frames_dir(job_id: Uuid) -> String {
+    format!("output/{}.frames", job_id)
+}
+
+pub struct FrameExport {
+    pub options: FrameExportOptions,
+}
+
+impl FrameExport {
+    pub fn new(options: FrameExportOptions) -> Self {
+        Self { options }
+    }
+
+    /// Roughly how many frames will come out, going by the probed frame count, or the
+    /// length and frame rate when there isn't one.
+    pub async fn estimated_frames(&self, job: &mut Job) -> anyhow::Result<u64> {
+        let frames = match self.options.select {
+            FrameSelection::All => source_frames(job).await?,
+            FrameSelection::Nth { nth } => source_frames(job).await? / nth as f64,
+            FrameSelection::Fps { fps } => job.duration().await? * fps,
+        };
+        Ok(frames.ceil().max(1.0) as u64)
+    }
+
+    /// Makes sure the export won't bury the server in images. Without an estimate there's
+    /// no telling, so that's turned away too.
+    pub async fn check_size(&self, job: &mut Job) -> Result<(), FramesError> {
+        match self.estimated_frames(job).await {
+            Ok(frames) if frames > MAX_FRAMES => Err(FramesError::TooManyFrames(frames)),
+            Ok(_) => Ok(()),
+            Err(_) => Err(FramesError::UnknownFrameCount),
+        }
+    }
+
+    /// Spawns ffmpeg to write out the frames, then zips them into the output. Works like
+    /// `Converter::convert` otherwise.
+    pub async fn export(
+        &self,
+        job: &mut Job,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<mpsc::Receiver<ProgressUpdate>> {
+        let (tx, rx) = mpsc::channel(1);
+        let job_id = job.id;
+        let extension = self.options.format.extension();
+
+        let dir = frames_dir(job_id);
+        // a leftover from an earlier, cancelled run would end up in the archive
+        if fs::metadata(&dir).await.is_ok() {
+            fs::remove_dir_all(&dir).await?;
+        }
+        fs::create_dir_all(&dir).await?;
+
+        let mut filters = Vec::new();
+        match self.options.select {
+            FrameSelection::All => {}
+            FrameSelection::Nth { nth } => filters.push(format!("select='not(mod(n\\,{}))'", nth)),
+            FrameSelection::Fps { fps } => filters.push(format!("fps={}", fps)),
+        }
+        if let Some(width) = self.options.width {
+            filters.push(thumbnail::scale_filter(width));
+        }
+
+        let mut args = vec![
+            "-hide_banner".to_string(),
+            "-loglevel".to_string(),
+            "error".to_string(),
+            "-progress".to_string(),
+            "pipe:1".to_string(),
+            "-i".to_string(),
+            job.input_path(),
+            "-map".to_string(),
+            "0:v:0".to_string(),
+        ];
+        if !filters.is_empty() {
+            args.extend(["-vf".to_string(), filters.join(",")]);
+        }
+        // only the fps filter should be deciding which frames there are
+        if !matches!(self.options.select, FrameSelection::Fps { .. }) {
+            args.extend(["-fps_mode".to_string(), "passthrough".to_string()]);
+        }
+        args.extend(self.options.format.encoder_args());
+        args.push(format!("{}/%06d.{}", dir, extension));
+
+        // the count only has to be close enough for a percentage
+        let context = ProgressContext {
+            total_frames: self.estimated_frames(job).await.ok(),
+            duration: None,
+            started: Instant::now(),
+            pass: 0,
+            passes: 1,
+        };
+        let output = format!("output/{}.zip", job_id);
+        let stem = job.output_stem();
+
+        tokio::spawn(async move {
+            let result = match run_ffmpeg(job_id, &args, &tx, &cancel, &context).await {
+                Ok(true) => {
+                    info!("zipping up the frames of job {}", job_id);
+                    let (dir, output) = (dir.clone(), output.clone());
+                    tokio::task::spawn_blocking(move || zip_dir(&dir, &output, &stem))
+                        .await
+                        .map_err(|e| anyhow!(e))
+                        .and_then(|result| {
+                            result.map_err(|e| anyhow!("failed to zip frames: {}", e))
+                        })
+                }
+                Ok(false) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!("{}", e);
+                let _ = tx.send(ProgressUpdate::Error(e.to_string())).await;
+                // a partial archive is worse than none, the runner fails jobs without one
+                fs::remove_file(&output).await.ok();
+            }
+            if let Err(e) = fs::remove_dir_all(&dir).await {
+                error!("failed to remove frames of job {}: {}", job_id, e);
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+/// How many frames the input has.
+async fn source_frames(job: &mut Job) -> anyhow::Result<f64> {
+    match job.known_total_frames().filter(|total| *total > 0) {
+        Some(total) => Ok(total as f64),
+        None => Ok(job.duration().await? * job.fps().await? as f64),
+    }
+}
+
+/// Zips up every file in `dir` under a folder named `stem`. The images are already
+/// compressed, so they're stored as they are.
+fn zip_dir(dir: &str, output: &str, stem: &str) -> io::Result<()> {
+    let mut names = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    if names.is_empty() {
+        return Err(io::Error::other("no frames came out"));
+    }
+
+    let mut zip = ZipWriter::new(File::create(output)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for name in names {
+        zip.start_file(format!("{}/{}", stem, name), options)?;
+        io::copy(&mut File::open(Path::new(dir).join(&name))?, &mut zip)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+use super::{
+    animation::{AnimationOptions, AnimationSettings},
+    codec::CodecError,
+    resize::Resize,
+};
+
+const DEFAULT_COLORS: u16 = 64;
+
+// the least we'll go down to when shrinking a gif to fit a size
+const MIN_WIDTH: u32 = 120;
+const MIN_FPS: u32 = 5;
+const MIN_COLORS: u16 = 16;
+
+/// How many times a size-limited gif gets encoded before we give up on it.
+pub const MAX_ATTEMPTS: usize = 6;
+
+/// paletteuse's dithering algorithms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+    None,
+    #[default]
+    Bayer,
+    Heckbert,
+    FloydSteinberg,
+    Sierra2,
+    #[serde(rename = "sierra2_4a")]
+    Sierra24a,
+    Sierra3,
+    Burkes,
+}
+
+impl Dither {
+    fn ffmpeg_name(&self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::Bayer => "bayer",
+            Dither::Heckbert => "heckbert",
+            Dither::FloydSteinberg => "floyd_steinberg",
+            Dither::Sierra2 => "sierra2",
+            Dither::Sierra24a => "sierra2_4a",
+            Dither::Sierra3 => "sierra3",
+            Dither::Burkes => "burkes",
+        }
+    }
+}
+
+/// Tweaks for gif outputs. Anything left out keeps the defaults.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GifOptions {
+    #[serde(flatten)]
+    pub animation: AnimationOptions,
+    /// Palette size, from 2 to 256. Defaults to 64.
+    pub colors: Option<u16>,
+    pub dither: Option<Dither>,
+    /// Keep encoding with smaller settings until the gif is at most this many bytes.
+    pub max_size: Option<u64>,
+}
+
+impl GifOptions {
+    pub fn validate(&self, resize: Option<&Resize>) -> Result<(), CodecError> {
+        self.animation.validate(resize)?;
+        if self
+            .colors
+            .is_some_and(|colors| !(2..=256).contains(&colors))
+        {
+            return Err(CodecError::InvalidAnimation(
+                "colors must be between 2 and 256",
+            ));
+        }
+        if self.max_size == Some(0) {
+            return Err(CodecError::InvalidAnimation("max size has to be positive"));
+        }
+        // shrinking a gif means picking its width, which a resize would fight with
+        if resize.is_some() && self.max_size.is_some() {
+            return Err(CodecError::InvalidAnimation(
+                "max size can't be combined with resize",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `GifOptions` with the defaults filled in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GifSettings {
+    animation: AnimationSettings,
+    colors: u16,
+    dither: Dither,
+}
+
+impl GifSettings {
+    pub fn new(options: Option<&GifOptions>, source_fps: u32) -> Self {
+        let options = options.cloned().unwrap_or_default();
+        Self {
+            animation: AnimationSettings::new(Some(&options.animation), source_fps),
+            colors: options.colors.unwrap_or(DEFAULT_COLORS),
+            dither: options.dither.unwrap_or_default(),
+        }
+    }
+
+    pub fn args(&self, resize: Option<&Resize>) -> Vec<String> {
+        vec![
+            "-filter_complex".to_string(),
+            format!(
+                "{},split[s0][s1];[s0]palettegen=max_colors={}[p];[s1][p]paletteuse=dither={}",
+                self.animation.filter(resize),
+                self.colors,
+                self.dither.ffmpeg_name()
+            ),
+            "-loop".to_string(),
+            self.animation.loop_count.to_string(),
+        ]
+    }
+
+    /// Smaller settings for another go at fitting under `max_size`, given the last
+    /// attempt came out at `size` bytes. `None` once there's nothing left to cut.
+    pub fn shrink(&self, size: u64, max_size: u64) -> Option<Self> {
+        // aim a little under, the size doesn't scale all that predictably
+        let ratio = max_size as f64 / size as f64 * 0.9;
+        let mut next = *self;
+
+        // size goes roughly with the area, so take most of it out of the width
+        let (width, fps) = (self.animation.width, self.animation.fps);
+        let min_width = MIN_WIDTH.min(width);
+        next.animation.width =
+            ((width as f64 * ratio.sqrt().clamp(0.5, 0.9)) as u32).max(min_width);
+
+        // way over, or the width can't go any lower -- drop frames and colours too
+        if ratio < 0.5 || next.animation.width == width {
+            next.animation.fps = (fps * 3 / 4).max(MIN_FPS.min(fps));
+            next.colors = (self.colors / 2).max(MIN_COLORS.min(self.colors));
+        }
+
+        (next != *self).then_some(next)
+    }
+}
@@ -6,6 +6,7 @@ use tokio::process::Command;
 use wgpu::Instance;
 use std::env::consts;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum ConverterGPU {
     AMD,
     Intel,
@@ -54,29 +55,6 @@ impl ConverterGPU {
             ConverterGPU::Apple => vec!["videotoolbox"],
         }
     }
-
-    pub fn hwaccel_args(&self) -> &[&str] {
-        match self {
-            ConverterGPU::AMD => {
-                if consts::OS == "linux" {
-                    &["-hwaccel", "vaapi", "-hwaccel_output_format", "vaapi"]
-                } else {
-                    &["-hwaccel", "amf"]
-                }
-            },
-
-            ConverterGPU::Intel => {
-                if consts::OS == "linux" {
-                    &["-hwaccel", "vaapi", "-hwaccel_output_format", "vaapi"]
-                } else {
-                    &["-hwaccel", "qsv"]
-                }
-            },
-
-            ConverterGPU::NVIDIA => &["-hwaccel", "cuda"],
-            ConverterGPU::Apple => &["-hwaccel", "videotoolbox"],
-        }
-    }
 }
 
 impl Display for ConverterGPU {
@@ -1,34 +1,118 @@
 use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
 use tokio::process::Command;
 use uuid::Uuid;
 
 const DEFAULT_BITRATE: u64 = 4 * 1_000_000;
 const BITRATE_MULTIPLIER: f64 = 2.5;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    #[default]
+    Uploaded,
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job currently has a conversion queued or in progress.
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+
+    /// Whether the job has produced (or tried and failed to produce) its output.
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Completed | JobStatus::Failed)
+    }
+}
+
+/// What the job was uploaded for, which decides how it can be started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "camelCase")]
+#[strum(serialize_all = "lowercase")]
+pub enum JobKind {
+    /// Converted into another format, chosen when it's started.
+    #[default]
+    Conversion,
+    /// Re-encoded into the same format to make it smaller.
+    Compression,
+    /// Numbered images encoded into a video, with `from` being their format.
+    ImageSequence,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Job {
     pub id: Uuid,
     pub auth: String,
+    #[serde(default)]
+    pub kind: JobKind,
     pub from: String,
     pub to: Option<String>,
-    pub completed: bool,
+    /// Name of the file as it was uploaded, used to name the download.
+    #[serde(default)]
+    pub filename: Option<String>,
+    #[serde(default)]
+    pub status: JobStatus,
     total_frames: Option<u64>,
     bitrate: Option<u64>,
     fps: Option<u32>,
+    duration: Option<f64>,
 }
 
 impl Job {
     pub fn new(auth_token: String, from: String) -> Self {
+        Self::with_id(Uuid::new_v4(), auth_token, from)
+    }
+
+    pub fn with_id(id: Uuid, auth_token: String, from: String) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id,
             auth: auth_token,
+            kind: JobKind::Conversion,
             from,
             to: None,
-            completed: false,
+            filename: None,
+            status: JobStatus::Uploaded,
             total_frames: None,
             bitrate: None,
             fps: None,
+            duration: None,
+        }
+    }
+
+    /// What ffmpeg reads: the uploaded file, or the pattern matching an image sequence's frames.
+    pub fn input_path(&self) -> String {
+        match self.kind {
+            JobKind::ImageSequence => format!("input/{}/%06d.{}", self.id, self.from),
+            _ => format!("input/{}.{}", self.id, self.from),
+        }
+    }
+
+    /// The file or directory holding the upload, which goes once the job is done with it.
+    pub fn input_location(&self) -> String {
+        match self.kind {
+            JobKind::ImageSequence => format!("input/{}", self.id),
+            _ => format!("input/{}.{}", self.id, self.from),
+        }
+    }
+
+    /// Marks the job as an image sequence of `frames` images, which saves counting them.
+    pub fn set_image_sequence(&mut self, frames: u64) {
+        self.kind = JobKind::ImageSequence;
+        self.total_frames = Some(frames);
+    }
+
+    /// Plays the frames back at `fps`, for inputs that don't have a frame rate of their
+    /// own. The duration follows from it once the frames have been counted.
+    pub fn set_frame_rate(&mut self, fps: u32) {
+        self.fps = Some(fps);
+        if let Some(frames) = self.total_frames {
+            self.duration = Some(frames as f64 / fps as f64);
         }
     }
 
@@ -49,7 +133,7 @@ impl Job {
                 "stream=bit_rate",
                 "-of",
                 "default=nokey=1:noprint_wrappers=1",
-                &format!("input/{}.{}", self.id, self.from),
+                &self.input_path(),
             ])
             .output()
             .await?;
@@ -69,7 +153,7 @@ impl Job {
             return Ok(total_frames);
         }
 
-        let path = format!("input/{}.{}", self.id, self.from);
+        let path = self.input_path();
 
         let output = Command::new("ffprobe")
             .args([
@@ -120,7 +204,7 @@ impl Job {
                 "stream=r_frame_rate",
                 "-of",
                 "default=nokey=1:noprint_wrappers=1",
-                &format!("input/{}.{}", self.id, self.from),
+                &self.input_path(),
             ])
             .output()
             .await?;
@@ -144,19 +228,284 @@ impl Job {
         Ok(fps)
     }
 
+    /// The uploaded file's name without its extension, or the job id if there isn't one.
+    pub fn output_stem(&self) -> String {
+        self.filename
+            .as_deref()
+            // some clients send the full path along
+            .and_then(|name| name.rsplit(['/', '\\']).next())
+            .map(|name| name.rsplit_once('.').map_or(name, |(stem, _)| stem))
+            .filter(|stem| !stem.is_empty())
+            .map_or_else(|| self.id.to_string(), str::to_string)
+    }
+
+    /// The uploaded file's name with a new extension, e.g. "holiday.mp4" -> "holiday.webm".
+    pub fn output_name(&self, ext: &str) -> String {
+        format!("{}.{}", self.output_stem(), ext)
+    }
+
+    /// Whether the input has a real video stream. Cover art embedded in audio files
+    /// shows up as a video stream too, so that doesn't count.
+    pub async fn has_video(&self) -> anyhow::Result<bool> {
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v",
+                "-show_entries",
+                "stream=codec_type:stream_disposition=attached_pic",
+                "-of",
+                "csv=p=0",
+                &self.input_path(),
+            ])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("ffprobe failed to read streams: {}", stderr));
+        }
+
+        // e.g. "video,0" -- the second field is the attached_pic disposition
+        let streams = String::from_utf8_lossy(&output.stdout);
+        Ok(streams.lines().any(|line| {
+            let mut fields = line.trim().split(',');
+            fields.next() == Some("video") && fields.next() != Some("1")
+        }))
+    }
+
+    /// Every stream in the input, in order.
+    pub async fn streams(&self) -> anyhow::Result<Vec<ProbedStream>> {
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-show_entries",
+                "stream=codec_name,codec_type:stream_disposition=attached_pic",
+                "-of",
+                "csv=p=0",
+                &self.input_path(),
+            ])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow::anyhow!("ffprobe failed to read streams: {}", stderr));
+        }
+
+        // e.g. "h264,video,0"
+        let streams = String::from_utf8_lossy(&output.stdout);
+        Ok(streams
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let mut fields = line.trim().split(',');
+                ProbedStream {
+                    codec: fields.next().unwrap_or_default().to_string(),
+                    kind: fields.next().unwrap_or_default().to_string(),
+                    attached_pic: fields.next() == Some("1"),
+                }
+            })
+            .collect())
+    }
+
+    /// Probes what progress gets measured against: the frame count, or the duration
+    /// for files without any video.
+    pub async fn probe_length(&mut self) -> anyhow::Result<()> {
+        if self.has_video().await? {
+            self.total_frames().await?;
+        } else {
+            self.duration().await?;
+        }
+        Ok(())
+    }
+
+    /// Takes over whatever `probe_length` found out about the same input, leaving
+    /// everything else as it is.
+    pub fn copy_length(&mut self, probed: &Job) {
+        self.total_frames = probed.total_frames.or(self.total_frames);
+        self.duration = probed.duration.or(self.duration);
+    }
+
+    /// Length of the input in seconds.
+    pub async fn duration(&mut self) -> anyhow::Result<f64> {
+        if let Some(duration) = self.duration {
+            return Ok(duration);
+        }
+
+        let output = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-show_entries",
+                "format=duration",
+                "-of",
+                "default=nokey=1:noprint_wrappers=1",
+                &self.input_path(),
+            ])
+            .output()
+            .await?;
+
+        let duration = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow::anyhow!("failed to parse duration: {}", e))?;
+        let duration = duration
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow::anyhow!("could not parse '{}' as duration", duration.trim()))?;
+
+        self.duration = Some(duration);
+        Ok(duration)
+    }
+
+    /// The frame count if it has already been probed.
+    pub fn known_total_frames(&self) -> Option<u64> {
+        self.total_frames
+    }
+
     pub async fn bitrate_and_fps(&mut self) -> anyhow::Result<(u64, u32)> {
         let (bitrate, fps) = (self.bitrate().await?, self.fps().await?);
         Ok((bitrate, fps))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Width and height of the first video stream in `path`, which can just as well be an image.
+pub async fn dimensions(path: &str) -> anyhow::Result<(u32, u32)> {
+    let output = Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-select_streams",
+            "v:0",
+            "-show_entries",
+            "stream=width,height",
+            "-of",
+            "csv=p=0:s=x",
+            path,
+        ])
+        .output()
+        .await?;
+
+    // e.g. "1920x1080"
+    let dimensions = String::from_utf8_lossy(&output.stdout);
+    let (width, height) = dimensions
+        .trim()
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .ok_or_else(|| anyhow::anyhow!("could not parse '{}' as dimensions", dimensions.trim()))?;
+    Ok((width, height))
+}
+
+/// One of the input's streams, as ffprobe sees it.
+#[derive(Clone, Debug)]
+pub struct ProbedStream {
+    /// ffprobe's name for the codec, e.g. "h264".
+    pub codec: String,
+    /// "video", "audio", "subtitle" and so on.
+    pub kind: String,
+    /// Cover art, which shows up as a video stream.
+    pub attached_pic: bool,
+}
+
+/// Latest progress of a job's conversion, kept around so it can be polled.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobProgress {
+    pub frame: Option<u64>,
+    pub fps: Option<f64>,
+    pub percent: Option<f64>,
+    /// Estimated seconds until the conversion is done.
+    pub eta: Option<f64>,
+    /// Bytes written to the output so far.
+    pub size: Option<u64>,
+    /// How many times faster than realtime ffmpeg is encoding.
+    pub speed: Option<f64>,
+    /// Output bitrate in kbit/s.
+    pub bitrate: Option<f64>,
+    /// Which pass of a multi-pass encode is running, counting from 1.
+    pub pass: Option<usize>,
+    pub passes: Option<usize>,
+    /// Which attempt at fitting a gif under its size limit is running, counting from 1.
+    pub attempt: Option<usize>,
+    pub error: Option<String>,
+}
+
+impl JobProgress {
+    pub fn apply(&mut self, update: &ProgressUpdate) {
+        match update {
+            ProgressUpdate::Frame(frame) => self.frame = Some(*frame),
+            ProgressUpdate::FPS(fps) => self.fps = Some(*fps),
+            ProgressUpdate::Percent(percent) => self.percent = Some(*percent),
+            ProgressUpdate::Eta(eta) => self.eta = Some(*eta),
+            ProgressUpdate::Size(size) => self.size = Some(*size),
+            ProgressUpdate::Speed(speed) => self.speed = Some(*speed),
+            ProgressUpdate::Bitrate(bitrate) => self.bitrate = Some(*bitrate),
+            ProgressUpdate::Pass { pass, passes } => {
+                self.pass = Some(*pass);
+                self.passes = Some(*passes);
+            }
+            ProgressUpdate::Attempt(attempt) => self.attempt = Some(*attempt),
+            ProgressUpdate::End => self.eta = Some(0.0),
+            ProgressUpdate::Error(_) => {}
+        }
+    }
+
+    /// The updates that would bring a fresh subscriber to where this is at. Passes and
+    /// attempts go first, since clients start their progress over on those.
+    pub fn updates(&self) -> Vec<ProgressUpdate> {
+        let mut updates = Vec::new();
+        if let Some(attempt) = self.attempt {
+            updates.push(ProgressUpdate::Attempt(attempt));
+        }
+        if let (Some(pass), Some(passes)) = (self.pass, self.passes) {
+            updates.push(ProgressUpdate::Pass { pass, passes });
+        }
+        updates.extend(self.frame.map(ProgressUpdate::Frame));
+        updates.extend(self.fps.map(ProgressUpdate::FPS));
+        updates.extend(self.percent.map(ProgressUpdate::Percent));
+        updates.extend(self.eta.map(ProgressUpdate::Eta));
+        updates.extend(self.size.map(ProgressUpdate::Size));
+        updates.extend(self.speed.map(ProgressUpdate::Speed));
+        updates.extend(self.bitrate.map(ProgressUpdate::Bitrate));
+        updates
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "camelCase")]
 pub enum ProgressUpdate {
     #[serde(rename = "frame", rename_all = "camelCase")]
     Frame(u64),
     #[serde(rename = "fps", rename_all = "camelCase")]
     FPS(f64),
+    #[serde(rename = "percent", rename_all = "camelCase")]
+    Percent(f64),
+    /// Seconds left, extrapolated from how long the conversion has taken so far.
+    #[serde(rename = "eta", rename_all = "camelCase")]
+    Eta(f64),
+    /// Bytes written to the output so far.
+    #[serde(rename = "size", rename_all = "camelCase")]
+    Size(u64),
+    /// Encoding speed as a multiple of realtime.
+    #[serde(rename = "speed", rename_all = "camelCase")]
+    Speed(f64),
+    /// Output bitrate in kbit/s.
+    #[serde(rename = "bitrate", rename_all = "camelCase")]
+    Bitrate(f64),
+    /// A new pass of a multi-pass encode has started. Frames count from 0 again, while
+    /// percent and eta cover all passes.
+    #[serde(rename = "pass", rename_all = "camelCase")]
+    Pass { pass: usize, passes: usize },
+    /// The last attempt at a size-limited gif came out too big, so it's being encoded
+    /// again with smaller settings. Progress starts over.
+    #[serde(rename = "attempt", rename_all = "camelCase")]
+    Attempt(usize),
+    /// ffmpeg has written its last progress report.
+    #[serde(rename = "end", rename_all = "camelCase")]
+    End,
     #[serde(rename = "error", rename_all = "camelCase")]
     Error(String),
 }
\ No newline at end of file
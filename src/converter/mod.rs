@@ -1,22 +1,36 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::{anyhow, Context};
-use format::{Conversion, ConverterFormat};
+use codec::{ConversionOptions, RateControl};
+use format::{Conversion, ConverterFormat, Source};
+use gif::GifSettings;
 use job::{Job, ProgressUpdate};
 use log::error;
 use log::info;
+use log::warn;
 use speed::ConversionSpeed;
 use tokio::fs;
 use tokio::io::AsyncBufReadExt as _;
 use tokio::io::BufReader;
 use tokio::process::Command;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
+pub mod animation;
+pub mod codec;
 pub mod format;
+pub mod frames;
+pub mod gif;
 pub mod gpu;
 pub mod job;
+pub mod queue;
+pub mod resize;
+pub mod runner;
 pub mod speed;
+pub mod storyboard;
+pub mod thumbnail;
 
 /// Finds the first available VA-API render device.
 /// e.g., /dev/dri/renderD128
@@ -47,25 +61,118 @@ pub struct Converter {
 }
 
 impl Converter {
-    pub fn new(from: ConverterFormat, to: ConverterFormat, speed: ConversionSpeed) -> Self {
+    pub fn new(
+        source: Source,
+        to: ConverterFormat,
+        speed: ConversionSpeed,
+        options: ConversionOptions,
+    ) -> Self {
         Self {
-            conversion: Conversion::new(from, to),
+            conversion: Conversion::new(source, to, options),
             speed,
         }
     }
 
-    pub async fn convert(&self, job: &mut Job) -> anyhow::Result<mpsc::Receiver<ProgressUpdate>> {
+    /// Spawns ffmpeg and streams its progress. Two-pass encodes run ffmpeg twice, one
+    /// pass after the other. Cancelling `cancel` kills the process, after which the
+    /// receiver closes like it would on a normal exit.
+    pub async fn convert(
+        &self,
+        job: &mut Job,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<mpsc::Receiver<ProgressUpdate>> {
         let (tx, rx) = mpsc::channel(1);
-        let input_filename = format!("input/{}.{}", job.id, self.conversion.from.to_string());
-        let output_filename = format!("output/{}.{}", job.id, self.conversion.to.to_string());
+        let output_filename = format!("output/{}.{}", job.id, self.conversion.to);
 
-        let (gpu, (bitrate, fps)) = tokio::try_join!(gpu::get_gpu(), job.bitrate_and_fps())?;
+        // images don't have a frame rate, so it's whatever was asked for
+        if let Source::ImageSequence { fps } = self.conversion.source {
+            job.set_frame_rate(fps);
+        }
+
+        // copying the streams over takes seconds, where re-encoding them can take hours
+        let streams = job.streams().await;
+        let remux_args = match &streams {
+            Ok(streams) => self.conversion.remux_args(streams),
+            Err(e) => {
+                warn!(
+                    "failed to probe streams of job {}, re-encoding: {}",
+                    job.id, e
+                );
+                None
+            }
+        };
+        let remuxing = remux_args.is_some();
+        if remuxing {
+            info!("remuxing job {} instead of re-encoding it", job.id);
+        }
+
+        // there's no video stream to probe when converting to audio (the input might be audio
+        // too), and nothing to tune when just copying the streams
+        let (gpu, (bitrate, fps)) = if self.conversion.to.is_audio() || remuxing {
+            (gpu::get_gpu().await?, (0, 0))
+        } else {
+            tokio::try_join!(gpu::get_gpu(), job.bitrate_and_fps())?
+        };
+
+        // percent is based on frames where we know how many there are, otherwise on time.
+        // audio outputs don't have any frames to count, remuxes go by time too since
+        // copied packets don't always line up with frames, and clips only cover some of them.
+        // animations drop frames to hit their own frame rate, so ffmpeg's count never gets
+        // anywhere near the source's
+        let options = &self.conversion.options;
+        let total_frames = if self.conversion.to.is_audio()
+            || self.conversion.to.is_animation()
+            || remuxing
+            || options.is_clipped()
+        {
+            None
+        } else {
+            job.known_total_frames().filter(|total| *total > 0)
+        };
+        let duration = match total_frames {
+            Some(_) => None,
+            None => job
+                .duration()
+                .await
+                .ok()
+                .map(|length| options.clipped_length(length))
+                .filter(|duration| *duration > 0.0),
+        };
+
+        // when aiming for a size, the bitrate is whatever fits that size over the clip's length.
+        // whether it fits at all was checked when the job was started
+        let bitrate = match options.target_size {
+            Some(target_size) if options.rate_control == RateControl::Size => {
+                let length = options.clipped_length(job.duration().await?);
+                if length <= 0.0 {
+                    return Err(anyhow!(
+                        "can't target a size without knowing how long the video is"
+                    ));
+                }
+                // not knowing what's in there means leaving room for audio, just in case
+                let has_audio = streams.as_ref().map_or(true, |streams| {
+                    streams.iter().any(|stream| stream.kind == "audio")
+                });
+                codec::target_video_bitrate(target_size, length, has_audio).ok_or_else(|| {
+                    anyhow!(
+                        "{} bytes is too small to fit {:.0}s of video",
+                        target_size,
+                        length
+                    )
+                })?
+            }
+            _ => bitrate,
+        };
 
         // Determine the encoder arguments first to see if we're using hardware.
-        let conversion_args = self
-            .conversion
-            .to_args(&self.speed, &gpu, bitrate, fps)
-            .await?;
+        let conversion_args = match remux_args {
+            Some(args) => args,
+            None => {
+                self.conversion
+                    .to_args(&self.speed, &gpu, bitrate, fps)
+                    .await?
+            }
+        };
 
         let encoder_is_hardware = conversion_args
             .iter()
@@ -91,86 +198,378 @@ impl Converter {
             ]);
         }
 
-        // Add input file
-        final_command.extend_from_slice(&["-i".to_string(), input_filename.to_string()]);
+        // Add input file, seeking to the start of the clip first
+        final_command.extend(options.seek_args());
+        final_command.extend(self.conversion.source.input_args(job));
+        final_command.extend(options.limit_args());
 
         // Add filters if needed
         if encoder_is_hardware {
             // This is a more robust filter chain. It uploads the frame, then uses the GPU's
             // own scaler to ensure the frame is in the NV12 format required by the encoder.
+            // Resizing rides along on the same scaler.
+            let filter = match options.resize.map(|resize| resize.vaapi_filters()) {
+                Some((Some(fit), size)) => {
+                    format!("{},hwupload,scale_vaapi={}:format=nv12", fit, size)
+                }
+                Some((None, size)) => format!("hwupload,scale_vaapi={}:format=nv12", size),
+                None => "hwupload,scale_vaapi=format=nv12".to_string(),
+            };
+            final_command.extend_from_slice(&["-vf".to_string(), filter]);
+        } else if let Some(resize) = options.resize {
+            // animated images already scale inside their own filters
+            if !self.conversion.to.is_animation() {
+                final_command.extend_from_slice(&["-vf".to_string(), resize.software_filter()]);
+            }
+        } else if matches!(self.conversion.source, Source::ImageSequence { .. })
+            && !self.conversion.to.is_animation()
+        {
+            // images can be any size, but yuv420p needs even ones
             final_command.extend_from_slice(&[
                 "-vf".to_string(),
-                "hwupload,scale_vaapi=format=nv12".to_string(),
+                "crop=trunc(iw/2)*2:trunc(ih/2)*2".to_string(),
             ]);
         }
 
-        // Add the rest of the arguments (encoder, bitrate, etc.) and the output file
-        final_command.extend(conversion_args);
-        final_command.push(output_filename);
+        // images tend to be rgb, which most players choke on once it's in a video
+        if matches!(self.conversion.source, Source::ImageSequence { .. })
+            && !encoder_is_hardware
+            && !self.conversion.to.is_animation()
+        {
+            final_command.extend_from_slice(&["-pix_fmt".to_string(), "yuv420p".to_string()]);
+        }
 
-        info!("running 'ffmpeg {}'", final_command.join(" "));
+        // gifs with a size limit get encoded over and over until they fit
+        if let Some(max_size) = options.gif_max_size() {
+            let gif = BoundedGif {
+                command: final_command,
+                output: output_filename,
+                settings: GifSettings::new(options.gif.as_ref(), fps),
+                max_size,
+            };
+            let context = ProgressContext {
+                total_frames,
+                duration,
+                started: Instant::now(),
+                pass: 0,
+                passes: 1,
+            };
+            tokio::spawn(encode_bounded_gif(job.id, gif, tx, cancel, context));
+            return Ok(rx);
+        }
 
-        let mut process = Command::new("ffmpeg")
-            .args(&final_command)
-            .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn()
-            .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
+        // two-pass only pays off when aiming for a size, and only some encoders can do it
+        let two_pass_encoder = conversion_args
+            .iter()
+            .position(|arg| arg == "-c:v")
+            .and_then(|i| conversion_args.get(i + 1))
+            .filter(|encoder| codec::supports_two_pass(encoder))
+            .filter(|_| self.conversion.options.rate_control == RateControl::Size)
+            .cloned();
 
-        let stderr = process
-            .stderr
-            .take()
-            .ok_or_else(|| anyhow!("failed to take stderr"))?;
+        // Add the rest of the arguments (encoder, bitrate, etc.) and the output file
+        final_command.extend(conversion_args);
+        let passes = match two_pass_encoder {
+            Some(encoder) => {
+                let log_prefix = format!("output/{}.pass", job.id);
+                // the first pass only gathers stats, so there's no need for audio or an output
+                let mut first = final_command.clone();
+                first.extend(codec::pass_args(&encoder, 1, &log_prefix));
+                first.extend(["-an", "-f", "null", NULL_OUTPUT].map(String::from));
 
-        let tx_arc = Arc::new(tx);
+                let mut second = final_command;
+                second.extend(codec::pass_args(&encoder, 2, &log_prefix));
+                second.push(output_filename);
+                vec![first, second]
+            }
+            None => {
+                final_command.push(output_filename);
+                vec![final_command]
+            }
+        };
 
-        let tx_clone = Arc::clone(&tx_arc);
+        let job_id = job.id;
+        let started = Instant::now();
 
         tokio::spawn(async move {
-            let mut lines = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = lines.next_line().await {
-                error!("{}", line);
-                let _ = tx_clone.send(ProgressUpdate::Error(line)).await;
+            let count = passes.len();
+            for (index, args) in passes.iter().enumerate() {
+                if count > 1 {
+                    info!("job {} starting pass {} of {}", job_id, index + 1, count);
+                    let pass = ProgressUpdate::Pass {
+                        pass: index + 1,
+                        passes: count,
+                    };
+                    if tx.send(pass).await.is_err() {
+                        break;
+                    }
+                }
+
+                let context = ProgressContext {
+                    total_frames,
+                    duration,
+                    started,
+                    pass: index,
+                    passes: count,
+                };
+                match run_ffmpeg(job_id, args, &tx, &cancel, &context).await {
+                    Ok(true) => {}
+                    // cancelled, or ffmpeg already said what went wrong
+                    Ok(false) => break,
+                    Err(e) => {
+                        error!("{}", e);
+                        let _ = tx.send(ProgressUpdate::Error(e.to_string())).await;
+                        break;
+                    }
+                }
+            }
+
+            if count > 1 {
+                remove_pass_logs(job_id).await;
             }
         });
 
-        let stdout = process
-            .stdout
-            .take()
-            .ok_or_else(|| anyhow!("failed to take stdout"))?;
-        let reader = BufReader::new(stdout);
+        Ok(rx)
+    }
+}
 
-        let tx_clone = Arc::clone(&tx_arc);
+#[cfg(windows)]
+const NULL_OUTPUT: &str = "NUL";
+#[cfg(not(windows))]
+const NULL_OUTPUT: &str = "/dev/null";
 
-        tokio::spawn(async move {
-            let mut lines = reader.lines();
-            while let Ok(Some(out)) = lines.next_line().await {
-                let mut map = HashMap::new();
-                for line in out.split('\n') {
-                    if let Some((k, v)) = line.split_once('=') {
-                        map.insert(k.trim(), v.trim());
-                    }
-                }
+/// Where a pass sits in the conversion, so its progress can be reported against the whole.
+#[derive(Clone, Copy)]
+struct ProgressContext {
+    total_frames: Option<u64>,
+    duration: Option<f64>,
+    started: Instant,
+    /// Counting from 0.
+    pass: usize,
+    passes: usize,
+}
+
+/// A gif that has to come out at `max_size` bytes or less.
+struct BoundedGif {
+    /// Everything up to the gif's own args.
+    command: Vec<String>,
+    output: String,
+    settings: GifSettings,
+    max_size: u64,
+}
 
-                let mut reports = Vec::new();
+/// Encodes the gif, shrinking its settings each time it comes out too big. If it still
+/// doesn't fit after `gif::MAX_ATTEMPTS`, the output is removed and the job fails.
+async fn encode_bounded_gif(
+    job_id: Uuid,
+    gif: BoundedGif,
+    tx: mpsc::Sender<ProgressUpdate>,
+    cancel: CancellationToken,
+    context: ProgressContext,
+) {
+    let mut settings = gif.settings;
+    for attempt in 1..=gif::MAX_ATTEMPTS {
+        if attempt > 1 && tx.send(ProgressUpdate::Attempt(attempt)).await.is_err() {
+            return;
+        }
 
-                if let Some(frame) = map.get("frame").and_then(|s| s.parse().ok()) {
-                    reports.push(ProgressUpdate::Frame(frame));
-                }
+        let mut args = gif.command.clone();
+        args.extend(settings.args(None));
+        args.push(gif.output.clone());
+
+        let context = ProgressContext {
+            started: Instant::now(),
+            ..context
+        };
+        match run_ffmpeg(job_id, &args, &tx, &cancel, &context).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                error!("{}", e);
+                let _ = tx.send(ProgressUpdate::Error(e.to_string())).await;
+                return;
+            }
+        }
+
+        let size = fs::metadata(&gif.output).await.map_or(0, |m| m.len());
+        if size <= gif.max_size {
+            return;
+        }
+        info!(
+            "gif for job {} came out at {} bytes, over its limit of {}",
+            job_id, size, gif.max_size
+        );
 
-                if let Some(fps) = map.get("fps").and_then(|s| s.parse().ok()) {
-                    reports.push(ProgressUpdate::FPS(fps));
+        match settings.shrink(size, gif.max_size) {
+            Some(smaller) => settings = smaller,
+            None => break,
+        }
+    }
+
+    // the runner fails jobs without an output, and reports the last error as the reason
+    let _ = tx
+        .send(ProgressUpdate::Error(format!(
+            "couldn't get the gif under {} bytes",
+            gif.max_size
+        )))
+        .await;
+    if let Err(e) = fs::remove_file(&gif.output).await {
+        error!("failed to remove oversized gif for job {}: {}", job_id, e);
+    }
+}
+
+/// Runs one ffmpeg process through to the end, streaming its progress into `tx`.
+/// Returns whether it exited successfully; cancelling `cancel` kills it.
+async fn run_ffmpeg(
+    job_id: Uuid,
+    args: &[String],
+    tx: &mpsc::Sender<ProgressUpdate>,
+    cancel: &CancellationToken,
+    context: &ProgressContext,
+) -> anyhow::Result<bool> {
+    info!("running 'ffmpeg {}'", args.join(" "));
+
+    let mut process = Command::new("ffmpeg")
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to spawn ffmpeg: {}", e))?;
+
+    let stderr = process
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("failed to take stderr"))?;
+    let stdout = process
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("failed to take stdout"))?;
+
+    let stderr_tx = tx.clone();
+    let stderr_task = tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            error!("{}", line);
+            let _ = stderr_tx.send(ProgressUpdate::Error(line)).await;
+        }
+    });
+
+    let mut lines = BufReader::new(stdout).lines();
+    // ffmpeg writes a block of key=value lines per report, ending in a progress= line
+    let mut block = HashMap::new();
+    let status = loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else {
+                    // stdout closed, ffmpeg is on its way out
+                    tokio::select! {
+                        status = process.wait() => break Some(status?),
+                        _ = cancel.cancelled() => break None,
+                    }
+                };
+                let Some((k, v)) = line.split_once('=') else {
+                    continue;
+                };
+                block.insert(k.trim().to_string(), v.trim().to_string());
+                if k.trim() != "progress" {
+                    continue;
                 }
 
+                let reports = progress_reports(&block, context);
+                block.clear();
+
                 for report in reports {
-                    if tx_clone.send(report).await.is_err() {
-                        break;
-                    }
+                    // nobody's listening anymore, but let ffmpeg finish anyway
+                    let _ = tx.send(report).await;
                 }
             }
-        });
+            _ = cancel.cancelled() => break None,
+        }
+    };
 
-        Ok(rx)
+    let Some(status) = status else {
+        info!("killing ffmpeg for cancelled job {}", job_id);
+        if let Err(e) = process.kill().await {
+            error!("failed to kill ffmpeg for job {}: {}", job_id, e);
+        }
+        return Ok(false);
+    };
+
+    let _ = stderr_task.await;
+    Ok(status.success())
+}
+
+/// Removes the stats files left behind by a two-pass encode. Encoders name them
+/// differently, but they all start with the prefix we gave them.
+async fn remove_pass_logs(job_id: Uuid) {
+    let prefix = format!("{}.pass", job_id);
+    let Ok(mut entries) = fs::read_dir("output").await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            if let Err(e) = fs::remove_file(entry.path()).await {
+                error!("failed to remove pass log for job {}: {}", job_id, e);
+            }
+        }
+    }
+}
+
+/// Turns one `-progress` block into the updates we report.
+fn progress_reports(
+    block: &HashMap<String, String>,
+    context: &ProgressContext,
+) -> Vec<ProgressUpdate> {
+    // missing values show up as "N/A", which just fails to parse
+    let get = |key: &str| block.get(key).map(String::as_str);
+    let frame = get("frame").and_then(|s| s.parse::<u64>().ok());
+    let out_time_us = get("out_time_us").and_then(|s| s.parse::<i64>().ok());
+
+    let mut reports = Vec::new();
+
+    if let Some(frame) = frame {
+        reports.push(ProgressUpdate::Frame(frame));
     }
-}
\ No newline at end of file
+
+    if let Some(fps) = get("fps").and_then(|s| s.parse().ok()) {
+        reports.push(ProgressUpdate::FPS(fps));
+    }
+
+    let percent = match (frame, context.total_frames, out_time_us, context.duration) {
+        (Some(frame), Some(total), _, _) => Some(frame as f64 / total as f64 * 100.0),
+        (_, _, Some(us), Some(duration)) => Some(us.max(0) as f64 / 1_000_000.0 / duration * 100.0),
+        _ => None,
+    }
+    .map(|percent| percent.clamp(0.0, 100.0))
+    // each pass gets an equal share of the whole
+    .map(|percent| (context.pass as f64 * 100.0 + percent) / context.passes as f64);
+
+    if let Some(percent) = percent {
+        reports.push(ProgressUpdate::Percent(percent));
+        if percent > 0.0 {
+            let elapsed = context.started.elapsed().as_secs_f64();
+            reports.push(ProgressUpdate::Eta(elapsed * (100.0 - percent) / percent));
+        }
+    }
+
+    if let Some(size) = get("total_size").and_then(|s| s.parse().ok()) {
+        reports.push(ProgressUpdate::Size(size));
+    }
+
+    // e.g. "1.53x"
+    if let Some(speed) = get("speed").and_then(|s| s.trim_end_matches('x').parse().ok()) {
+        reports.push(ProgressUpdate::Speed(speed));
+    }
+
+    // e.g. "1234.5kbits/s"
+    if let Some(bitrate) = get("bitrate").and_then(|s| s.trim_end_matches("kbits/s").parse().ok()) {
+        reports.push(ProgressUpdate::Bitrate(bitrate));
+    }
+
+    if get("progress") == Some("end") && context.pass + 1 == context.passes {
+        reports.push(ProgressUpdate::End);
+    }
+
+    reports
+}
@@ -0,0 +1,159 @@
+use std::{
+    collections::VecDeque,
+    env,
+    future::{self, Future},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::FutureExt as _;
+use lazy_static::lazy_static;
+use log::warn;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+const DEFAULT_MAX_CONCURRENT_CONVERSIONS: usize = 1;
+
+lazy_static! {
+    pub static ref QUEUE: ConversionQueue = ConversionQueue::from_env();
+}
+
+/// FIFO queue that caps how many ffmpeg processes run at the same time.
+pub struct ConversionQueue {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    waiting: Mutex<VecDeque<Uuid>>,
+    changed: watch::Sender<()>,
+}
+
+impl ConversionQueue {
+    /// Reads the limit from `VERTD_MAX_CONCURRENT_CONVERSIONS` (defaults to 1).
+    pub fn from_env() -> Self {
+        let max_concurrent = match env::var("VERTD_MAX_CONCURRENT_CONVERSIONS") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    warn!(
+                        "invalid value for VERTD_MAX_CONCURRENT_CONVERSIONS: '{}'. using {}.",
+                        value, DEFAULT_MAX_CONCURRENT_CONVERSIONS
+                    );
+                    DEFAULT_MAX_CONCURRENT_CONVERSIONS
+                }
+            },
+            Err(_) => DEFAULT_MAX_CONCURRENT_CONVERSIONS,
+        };
+
+        Self::new(max_concurrent)
+    }
+
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            waiting: Mutex::new(VecDeque::new()),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Puts the job at the back of the queue. The conversion may start once the
+    /// returned ticket yields a permit.
+    pub fn enqueue(&'static self, id: Uuid) -> QueueTicket {
+        let mut waiting = self.waiting.lock().unwrap();
+        waiting.push_back(id);
+        // the semaphore hands out permits in the order they were first polled for, and
+        // `acquire_owned` doesn't get in line until then. polling it once while `waiting`
+        // is still locked keeps the two in step
+        let mut acquire: Pin<Box<dyn Future<Output = OwnedSemaphorePermit> + Send>> = Box::pin(
+            self.permits
+                .clone()
+                .acquire_owned()
+                .map(|permit| permit.expect("the conversion queue's semaphore is never closed")),
+        );
+        if let Some(permit) = (&mut acquire).now_or_never() {
+            // a finished future can't be polled again, so hand the permit over as is
+            acquire = Box::pin(future::ready(permit));
+        }
+        drop(waiting);
+
+        QueueTicket {
+            id,
+            queue: self,
+            changed: self.changed.subscribe(),
+            acquire,
+            last_position: None,
+            done: false,
+        }
+    }
+
+    /// 1-based position of the job among those still waiting.
+    pub fn position(&self, id: &Uuid) -> Option<usize> {
+        self.waiting
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|queued| queued == id)
+            .map(|i| i + 1)
+    }
+
+    fn leave(&self, id: &Uuid) {
+        self.waiting.lock().unwrap().retain(|queued| queued != id);
+        self.changed.send_replace(());
+    }
+}
+
+pub enum QueueEvent {
+    /// The job is still waiting and is now at this (1-based) position.
+    Position(usize),
+    /// The job may run. The slot is freed when the permit is dropped.
+    Ready(OwnedSemaphorePermit),
+}
+
+pub struct QueueTicket {
+    id: Uuid,
+    queue: &'static ConversionQueue,
+    changed: watch::Receiver<()>,
+    acquire: Pin<Box<dyn Future<Output = OwnedSemaphorePermit> + Send>>,
+    last_position: Option<usize>,
+    done: bool,
+}
+
+impl QueueTicket {
+    /// Waits until either the job's position changes or it's allowed to start.
+    /// If there's a free slot straight away, no position is ever reported.
+    pub async fn next(&mut self) -> QueueEvent {
+        loop {
+            let position = self.queue.position(&self.id).unwrap_or(1);
+            if self.last_position != Some(position) {
+                if let Some(permit) = (&mut self.acquire).now_or_never() {
+                    return self.ready(permit);
+                }
+                self.last_position = Some(position);
+                return QueueEvent::Position(position);
+            }
+
+            tokio::select! {
+                permit = &mut self.acquire => return self.ready(permit),
+                _ = self.changed.changed() => {}
+            }
+        }
+    }
+
+    fn ready(&mut self, permit: OwnedSemaphorePermit) -> QueueEvent {
+        self.done = true;
+        self.queue.leave(&self.id);
+        QueueEvent::Ready(permit)
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        // gave up while still waiting -- let everyone behind us move up
+        if !self.done {
+            self.queue.leave(&self.id);
+        }
+    }
+}
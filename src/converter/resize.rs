@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+
+use super::codec::CodecError;
+
+/// Anything bigger than 8K is almost certainly a typo.
+const MAX_DIMENSION: u32 = 8192;
+const MAX_PERCENT: u32 = 400;
+
+/// How to resize the video. Whatever the mode, the output ends up with even dimensions,
+/// since that's what h264 and friends need for yuv420p.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum Resize {
+    /// Shrink to fit within the given size, keeping the aspect ratio. Never upscales.
+    Fit {
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    /// Exactly this size, letterboxing whatever doesn't fit the aspect ratio.
+    Pad { width: u32, height: u32 },
+    /// Exactly this size, cropping whatever doesn't fit the aspect ratio.
+    Crop { width: u32, height: u32 },
+    /// Scale both sides by this many percent.
+    Percent { percent: u32 },
+}
+
+impl Resize {
+    pub fn validate(&self) -> Result<(), CodecError> {
+        let in_range = |n: u32| (2..=MAX_DIMENSION).contains(&n);
+        let valid = match *self {
+            Resize::Fit { width, height } => {
+                (width.is_some() || height.is_some())
+                    && width.is_none_or(in_range)
+                    && height.is_none_or(in_range)
+            }
+            Resize::Pad { width, height } | Resize::Crop { width, height } => {
+                in_range(width) && in_range(height)
+            }
+            Resize::Percent { percent } => (1..=MAX_PERCENT).contains(&percent),
+        };
+
+        if !valid {
+            return Err(CodecError::InvalidResize);
+        }
+        Ok(())
+    }
+
+    /// Crops or pads the frame to the target aspect ratio, so the scaler only has to
+    /// scale. The GPU has no equivalent we can count on, so this always runs on the CPU.
+    fn fit_aspect(&self) -> Option<String> {
+        match *self {
+            // pad can't go smaller than the input, so odd sizes round up instead
+            Resize::Pad { width, height } => Some(format!(
+                "pad=w='{}':h='{}':x='(ow-iw)/2':y='(oh-ih)/2'",
+                even_up(&format!("max(iw,ih*{}/{})", width, height)),
+                even_up(&format!("max(ih,iw*{}/{})", height, width)),
+            )),
+            Resize::Crop { width, height } => Some(format!(
+                "crop=w='{}':h='{}'",
+                even(&format!("min(iw,ih*{}/{})", width, height)),
+                even(&format!("min(ih,iw*{}/{})", height, width)),
+            )),
+            Resize::Fit { .. } | Resize::Percent { .. } => None,
+        }
+    }
+
+    /// `w=...:h=...` for the scaler, as ffmpeg expressions of the input size.
+    fn scale_size(&self) -> String {
+        let factor = match *self {
+            Resize::Fit { width, height } => {
+                let width = width.map(|width| format!("{}/iw", width));
+                let height = height.map(|height| format!("{}/ih", height));
+                match (width, height) {
+                    (Some(width), Some(height)) => format!("min(1,min({},{}))", width, height),
+                    (Some(side), None) | (None, Some(side)) => format!("min(1,{})", side),
+                    (None, None) => "1".to_string(),
+                }
+            }
+            Resize::Pad { width, height } | Resize::Crop { width, height } => {
+                // the aspect ratio already matches, so just make it exact
+                return format!("w={}:h={}", width & !1, height & !1);
+            }
+            Resize::Percent { percent } => format!("{}/100", percent),
+        };
+
+        format!(
+            "w='{}':h='{}'",
+            even(&format!("iw*{}", factor)),
+            even(&format!("ih*{}", factor)),
+        )
+    }
+
+    /// The whole resize as a software filter chain.
+    pub fn software_filter(&self) -> String {
+        let scale = format!("scale={}:flags=lanczos", self.scale_size());
+        match self.fit_aspect() {
+            Some(fit) => format!("{},{}", fit, scale),
+            None => scale,
+        }
+    }
+
+    /// The resize for the VA-API path: filters to run before the upload, and the size
+    /// for `scale_vaapi`.
+    pub fn vaapi_filters(&self) -> (Option<String>, String) {
+        (self.fit_aspect(), self.scale_size())
+    }
+}
+
+/// Rounds an expression down to an even number, but not below 2.
+fn even(expr: &str) -> String {
+    format!("max(2,trunc({}/2)*2)", expr)
+}
+
+/// Rounds an expression up to an even number.
+fn even_up(expr: &str) -> String {
+    format!("ceil({}/2)*2", expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_rounds_up_and_crop_rounds_down() {
+        let pad = Resize::Pad {
+            width: 16,
+            height: 9,
+        };
+        assert!(pad
+            .software_filter()
+            .starts_with("pad=w='ceil(max(iw,ih*16/9)/2)*2':h='ceil(max(ih,iw*9/16)/2)*2'"));
+
+        let crop = Resize::Crop {
+            width: 16,
+            height: 9,
+        };
+        assert!(crop.software_filter().starts_with(
+            "crop=w='max(2,trunc(min(iw,ih*16/9)/2)*2)':h='max(2,trunc(min(ih,iw*9/16)/2)*2)'"
+        ));
+    }
+}
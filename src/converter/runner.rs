@@ -0,0 +1,572 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use discord_webhook2::{message, webhook::DiscordWebhook};
+use log::{error, info, warn};
+use tokio::{
+    fs,
+    sync::{broadcast, mpsc},
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{
+    codec::{self, CodecError, ConversionOptions, RateControl},
+    format::{ConverterFormat, Source},
+    frames::{FrameExport, FrameExportOptions, FramesError},
+    job::{Job, JobKind, JobProgress, JobStatus, ProgressUpdate},
+    queue::{QueueEvent, QUEUE},
+    speed::ConversionSpeed,
+    storyboard::{Storyboard, StoryboardError, StoryboardOptions},
+    Converter,
+};
+use crate::{
+    http::services::websocket::Message,
+    state::{self, APP_STATE},
+    OUTPUT_LIFETIME,
+};
+
+/// Shrinking a file by more than this is asking for a blurry mess.
+const MAX_REDUCTION: u8 = 95;
+/// Highest frame rate an image sequence can be played back at.
+const MAX_SEQUENCE_FPS: u32 = 120;
+
+/// Where a job's progress gets reported to. Every subscriber gets its own copy.
+pub type Events = broadcast::Sender<Message>;
+
+pub struct StartedJob {
+    /// Cancels the conversion.
+    pub cancel: CancellationToken,
+    /// Subscribed before the conversion was spawned, so nothing gets missed.
+    pub events: broadcast::Receiver<Message>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("invalid input format")]
+    InvalidInputFormat,
+    #[error("invalid output format")]
+    InvalidOutputFormat,
+    #[error("audio can only be converted to audio formats")]
+    AudioToVideo,
+    #[error("this job was uploaded for {0}")]
+    WrongKind(JobKind),
+    #[error("reduction must be between 1 and {} percent", MAX_REDUCTION)]
+    InvalidReduction,
+    #[error("fps must be between 1 and {}", MAX_SEQUENCE_FPS)]
+    InvalidFrameRate,
+    #[error("the input has no video to take frames from")]
+    NoVideo,
+    #[error("couldn't work out how long the input is")]
+    UnknownLength,
+    #[error("{size} bytes is too small to fit {length:.0}s of video")]
+    TargetTooSmall { size: u64, length: f64 },
+    #[error(transparent)]
+    UnsupportedCodec(#[from] CodecError),
+    #[error(transparent)]
+    InvalidStoryboard(#[from] StoryboardError),
+    #[error(transparent)]
+    InvalidFrameExport(#[from] FramesError),
+    #[error("failed to read the upload: {0}")]
+    ReadInput(std::io::Error),
+    #[error("job already completed")]
+    AlreadyCompleted,
+    #[error("job already running")]
+    AlreadyRunning,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CancelError {
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("job is not running")]
+    NotRunning,
+}
+
+/// What a started job turns its input into.
+enum Task {
+    Conversion(Converter),
+    Storyboard(Storyboard),
+    Frames(FrameExport),
+}
+
+impl Task {
+    /// Extension of the file it produces.
+    fn output_ext(&self) -> String {
+        match self {
+            Task::Conversion(converter) => converter.conversion.to.to_string(),
+            Task::Storyboard(storyboard) => storyboard.format.extension().to_string(),
+            Task::Frames(_) => "zip".to_string(),
+        }
+    }
+
+    async fn run(
+        &self,
+        job: &mut Job,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<mpsc::Receiver<ProgressUpdate>> {
+        match self {
+            Task::Conversion(converter) => converter.convert(job, cancel).await,
+            Task::Storyboard(storyboard) => storyboard.generate(job, cancel).await,
+            Task::Frames(export) => export.export(job, cancel).await,
+        }
+    }
+}
+
+/// Validates and claims the job, then queues its conversion in the background.
+pub async fn start_job(
+    job_id: Uuid,
+    token: &str,
+    to: &str,
+    speed: ConversionSpeed,
+    options: ConversionOptions,
+) -> Result<StartedJob, StartError> {
+    let mut job = authorized_job(job_id, token, JobKind::Conversion).await?;
+
+    let from = job
+        .from
+        .parse::<ConverterFormat>()
+        .map_err(|_| StartError::InvalidInputFormat)?;
+    let to = to
+        .parse::<ConverterFormat>()
+        .map_err(|_| StartError::InvalidOutputFormat)?;
+
+    if from.is_audio() && !to.is_audio() {
+        return Err(StartError::AudioToVideo);
+    }
+    options.validate(&to)?;
+    check_length(&mut job, &options).await?;
+
+    let converter = Converter::new(Source::File(from), to, speed, options);
+    claim_and_run(job, Task::Conversion(converter)).await
+}
+
+/// Like `start_job`, but re-encodes into the same format, aiming for a file that's
+/// `reduction` percent smaller than the upload.
+pub async fn start_compression(
+    job_id: Uuid,
+    token: &str,
+    reduction: u8,
+    speed: ConversionSpeed,
+) -> Result<StartedJob, StartError> {
+    let mut job = authorized_job(job_id, token, JobKind::Compression).await?;
+
+    if !(1..=MAX_REDUCTION).contains(&reduction) {
+        return Err(StartError::InvalidReduction);
+    }
+
+    let format = job
+        .from
+        .parse::<ConverterFormat>()
+        .map_err(|_| StartError::InvalidInputFormat)?;
+    let input_size = fs::metadata(job.input_location())
+        .await
+        .map_err(StartError::ReadInput)?
+        .len();
+
+    // compression is just a conversion into the same format that's aiming for a size
+    let options = ConversionOptions {
+        rate_control: RateControl::Size,
+        target_size: Some(input_size * (100 - reduction as u64) / 100),
+        ..Default::default()
+    };
+    options.validate(&format)?;
+    check_length(&mut job, &options).await?;
+
+    let converter = Converter::new(Source::File(format), format, speed, options);
+    claim_and_run(job, Task::Conversion(converter)).await
+}
+
+/// Like `start_job`, but encodes an uploaded image sequence into a video playing at `fps`.
+pub async fn start_sequence(
+    job_id: Uuid,
+    token: &str,
+    to: &str,
+    fps: u32,
+    speed: ConversionSpeed,
+    options: ConversionOptions,
+) -> Result<StartedJob, StartError> {
+    let mut job = authorized_job(job_id, token, JobKind::ImageSequence).await?;
+
+    if !(1..=MAX_SEQUENCE_FPS).contains(&fps) {
+        return Err(StartError::InvalidFrameRate);
+    }
+    let to = to
+        .parse::<ConverterFormat>()
+        .map_err(|_| StartError::InvalidOutputFormat)?;
+    if to.is_audio() {
+        return Err(StartError::InvalidOutputFormat);
+    }
+    options.validate(&to)?;
+    // the length follows from the frame rate
+    job.set_frame_rate(fps);
+    check_length(&mut job, &options).await?;
+
+    let converter = Converter::new(Source::ImageSequence { fps }, to, speed, options);
+    claim_and_run(job, Task::Conversion(converter)).await
+}
+
+/// Like `start_job`, but tiles frames from across the upload into a single image instead
+/// of converting it.
+pub async fn start_storyboard(
+    job_id: Uuid,
+    token: &str,
+    options: StoryboardOptions,
+) -> Result<StartedJob, StartError> {
+    let mut job = video_job(job_id, token).await?;
+    options.validate()?;
+    // the frames are spaced out over the whole input
+    if !job.duration().await.is_ok_and(|duration| duration > 0.0) {
+        return Err(StartError::UnknownLength);
+    }
+
+    claim_and_run(job, Task::Storyboard(Storyboard::new(&options))).await
+}
+
+/// Like `start_job`, but dumps frames of the upload as images into a zip instead of
+/// converting it.
+pub async fn start_frame_export(
+    job_id: Uuid,
+    token: &str,
+    options: FrameExportOptions,
+) -> Result<StartedJob, StartError> {
+    let mut job = video_job(job_id, token).await?;
+    options.validate()?;
+    let export = FrameExport::new(options);
+    export.check_size(&mut job).await?;
+
+    claim_and_run(job, Task::Frames(export)).await
+}
+
+/// Checks the options that depend on how long the input is, so they can be fixed and
+/// sent again rather than failing the job once it's had its turn in the queue.
+async fn check_length(job: &mut Job, options: &ConversionOptions) -> Result<(), StartError> {
+    let target_size = options
+        .target_size
+        .filter(|_| options.rate_control == RateControl::Size);
+    if !options.is_clipped() && target_size.is_none() {
+        return Ok(());
+    }
+    let length = job
+        .duration()
+        .await
+        .map_err(|_| StartError::UnknownLength)?;
+    if options.is_clipped() && options.clipped_length(length) <= 0.0 {
+        return Err(CodecError::InvalidClip("it starts after the end of the input").into());
+    }
+
+    if let Some(size) = target_size {
+        let length = options.clipped_length(length);
+        if length <= 0.0 {
+            return Err(StartError::UnknownLength);
+        }
+        // same as the conversion will, leave room for audio unless there's surely none
+        let has_audio = job.streams().await.map_or(true, |streams| {
+            streams.iter().any(|stream| stream.kind == "audio")
+        });
+        if codec::target_video_bitrate(size, length, has_audio).is_none() {
+            return Err(StartError::TargetTooSmall { size, length });
+        }
+    }
+    Ok(())
+}
+
+/// Looks up the job, making sure the token matches and it was uploaded as `kind`.
+async fn authorized_job(job_id: Uuid, token: &str, kind: JobKind) -> Result<Job, StartError> {
+    let job = {
+        let app_state = APP_STATE.lock().await;
+        app_state.get_job(&job_id).cloned()
+    }
+    .ok_or(StartError::JobNotFound)?;
+
+    if job.auth != token {
+        return Err(StartError::InvalidToken);
+    }
+
+    if job.kind != kind {
+        return Err(StartError::WrongKind(job.kind));
+    }
+
+    Ok(job)
+}
+
+/// Like `authorized_job`, for the tasks that take frames from an upload rather than
+/// converting it.
+async fn video_job(job_id: Uuid, token: &str) -> Result<Job, StartError> {
+    let job = authorized_job(job_id, token, JobKind::Conversion).await?;
+    // a failed probe most likely means there's no video either
+    if !job.has_video().await.unwrap_or(false) {
+        return Err(StartError::NoVideo);
+    }
+    Ok(job)
+}
+
+async fn claim_and_run(job: Job, task: Task) -> Result<StartedJob, StartError> {
+    let job_id = job.id;
+    // claim the job so it can't be started twice at once
+    let cancel = CancellationToken::new();
+    let (events, receiver) = {
+        let mut app_state = APP_STATE.lock().await;
+        match app_state.get_job(&job_id).map(|job| job.status) {
+            Some(status) if status.is_finished() => return Err(StartError::AlreadyCompleted),
+            Some(status) if status.is_active() => return Err(StartError::AlreadyRunning),
+            Some(_) => {
+                app_state.update_job(&job_id, |job| {
+                    job.to = Some(task.output_ext());
+                    job.status = JobStatus::Queued;
+                });
+                app_state.cancellations.insert(job_id, cancel.clone());
+                app_state.progress.insert(job_id, JobProgress::default());
+            }
+            None => return Err(StartError::JobNotFound),
+        }
+
+        let events = app_state.job_events(job_id);
+        let receiver = events.subscribe();
+        (events, receiver)
+    };
+
+    tokio::spawn(run_job(job, task, cancel.clone(), events));
+
+    Ok(StartedJob {
+        cancel,
+        events: receiver,
+    })
+}
+
+pub async fn cancel_job(job_id: Uuid, token: &str) -> Result<(), CancelError> {
+    let app_state = APP_STATE.lock().await;
+    let job = app_state.get_job(&job_id).ok_or(CancelError::JobNotFound)?;
+
+    if job.auth != token {
+        return Err(CancelError::InvalidToken);
+    }
+
+    app_state
+        .cancellations
+        .get(&job_id)
+        .ok_or(CancelError::NotRunning)?
+        .cancel();
+
+    Ok(())
+}
+
+fn emit(events: &Events, message: Message) {
+    // no subscribers is fine, the job can run detached
+    events.send(message).ok();
+}
+
+/// Runs the task, then closes its event feed so subscribers know it's over.
+async fn run_job(job: Job, task: Task, cancel: CancellationToken, events: Events) {
+    let job_id = job.id;
+    convert_job(job, task, cancel, &events).await;
+
+    let mut app_state = APP_STATE.lock().await;
+    app_state.close_events(&job_id, &events);
+}
+
+/// Waits for a conversion slot, runs the task and reports back over `events`.
+/// Sends are best-effort since the client may have disconnected in the meantime.
+async fn convert_job(mut job: Job, task: Task, cancel: CancellationToken, events: &Events) {
+    let job_id = job.id;
+    let to = task.output_ext();
+
+    // wait for a free conversion slot, telling the client where they are in line
+    let mut ticket = QUEUE.enqueue(job_id);
+    let permit = loop {
+        tokio::select! {
+            event = ticket.next() => match event {
+                QueueEvent::Position(position) => {
+                    emit(events, Message::QueuePosition { job_id, position });
+                }
+                QueueEvent::Ready(permit) => break Some(permit),
+            },
+            _ = cancel.cancelled() => break None,
+        }
+    };
+    drop(ticket);
+
+    let Some(permit) = permit else {
+        finish_cancelled(job_id, events).await;
+        return;
+    };
+
+    {
+        let mut app_state = APP_STATE.lock().await;
+        app_state.update_job(&job_id, |job| job.status = JobStatus::Running);
+    }
+
+    emit(events, Message::JobStarted { job_id });
+
+    let mut rx = match task.run(&mut job, cancel.clone()).await {
+        Ok(rx) => rx,
+        Err(e) => {
+            let message = format!("failed to convert: {}", e);
+            let mut app_state = APP_STATE.lock().await;
+            app_state.update_job(&job_id, |job| job.status = JobStatus::Failed);
+            app_state.cancellations.remove(&job_id);
+            if let Some(progress) = app_state.progress.get_mut(&job_id) {
+                progress.error = Some(message.clone());
+            }
+            drop(app_state);
+
+            emit(events, Message::Error { message });
+
+            // finished jobs are left alone by `expire_input`, so clean up like any other
+            state::expire_output(job_id, to, OUTPUT_LIFETIME);
+            if let Err(e) = state::remove_input(&job.input_location()).await {
+                error!("failed to remove input file: {}", e);
+            }
+            return;
+        }
+    };
+
+    let mut logs = Vec::new();
+
+    while let Some(update) = rx.recv().await {
+        {
+            let mut app_state = APP_STATE.lock().await;
+            if let Some(progress) = app_state.progress.get_mut(&job_id) {
+                progress.apply(&update);
+            }
+        }
+
+        match update {
+            ProgressUpdate::Error(err) => {
+                logs.push(err);
+            }
+            _ => {
+                emit(events, Message::ProgressUpdate(update));
+            }
+        }
+    }
+
+    // ffmpeg has exited, let the next job in
+    drop(permit);
+
+    if cancel.is_cancelled() {
+        let path = format!("output/{}.{}", job_id, to);
+        if let Err(e) = fs::remove_file(&path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove partial output file: {}", e);
+            }
+        }
+        finish_cancelled(job_id, events).await;
+        return;
+    }
+
+    // check if output/{}.{} exists and isn't empty
+    let is_empty = fs::metadata(&format!("output/{}.{}", job_id, to))
+        .await
+        .map(|m| m.len() == 0)
+        .unwrap_or(true);
+
+    let mut app_state = APP_STATE.lock().await;
+    app_state.update_job(&job_id, |job| {
+        job.status = if is_empty {
+            JobStatus::Failed
+        } else {
+            JobStatus::Completed
+        }
+    });
+    app_state.cancellations.remove(&job_id);
+    if let Some(progress) = app_state.progress.get_mut(&job_id) {
+        if is_empty {
+            // ffmpeg's last complaint is usually the one that matters
+            let reason = logs
+                .last()
+                .map(String::as_str)
+                .unwrap_or("no output was produced");
+            progress.error = Some(format!("conversion failed: {}", reason));
+        } else {
+            progress.percent = Some(100.0);
+        }
+    }
+    drop(app_state);
+
+    if is_empty {
+        log::error!("job {} failed", job_id);
+        emit(
+            events,
+            Message::Error {
+                message: "oops -- your job failed! maddie has been notified :)".to_string(),
+            },
+        );
+
+        let from = job.from.clone();
+        let to = to.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_job_failure(job_id, from, to, logs.join("\n")).await {
+                log::error!("failed to handle job failure: {}", e);
+            }
+        });
+    } else {
+        emit(events, Message::JobFinished { job_id });
+    }
+
+    state::expire_output(job_id, to, OUTPUT_LIFETIME);
+
+    if let Err(e) = state::remove_input(&job.input_location()).await {
+        error!("failed to remove input file: {}", e);
+        emit(
+            events,
+            Message::Error {
+                message: format!("failed to remove input file: {}", e),
+            },
+        );
+    }
+}
+
+/// Marks the job as cancelled. The input is kept around so it can be started again.
+async fn finish_cancelled(job_id: Uuid, events: &Events) {
+    let mut app_state = APP_STATE.lock().await;
+    app_state.update_job(&job_id, |job| job.status = JobStatus::Cancelled);
+    app_state.cancellations.remove(&job_id);
+    drop(app_state);
+
+    info!("job {} cancelled", job_id);
+    emit(events, Message::JobCancelled { job_id });
+}
+
+async fn handle_job_failure(
+    job_id: Uuid,
+    from: String,
+    to: String,
+    logs: String,
+) -> anyhow::Result<()> {
+    // Check for the webhook URL. If it's not set or empty, just warn and exit gracefully.
+    let client_url = match env::var("WEBHOOK_URL") {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            warn!("WEBHOOK_URL not set. Skipping failure notification.");
+            return Ok(());
+        }
+    };
+
+    let mentions = env::var("WEBHOOK_PINGS").unwrap_or_else(|_| "".to_string());
+
+    let mut files = BTreeMap::new();
+    files.insert(format!("{}.log", job_id), logs.as_bytes().to_vec());
+
+    let client = DiscordWebhook::new(&client_url)?;
+    let message = message::Message::new(|m| {
+        m.content(format!("🚨🚨🚨 {}", mentions)).embed(|e| {
+            e.title("vertd job failed!")
+                .field(|f| f.name("job id").value(job_id))
+                .field(|f| f.name("from").value(format!(".{}", from)).inline(true))
+                .field(|f| f.name("to").value(format!(".{}", to)).inline(true))
+                .color(0xff83fa)
+        })
+    });
+
+    client.send_with_files(&message, files).await?;
+
+    Ok(())
+}
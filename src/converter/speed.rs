@@ -1,9 +1,13 @@
 use log::warn;
 use serde::{Deserialize, Serialize};
 
-use super::{format::ConverterFormat, gpu::ConverterGPU};
+use super::{
+    codec::{self, ConversionOptions, RateControl},
+    format::ConverterFormat,
+    gpu::ConverterGPU,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ConversionSpeed {
     UltraFast,
@@ -26,65 +30,212 @@ impl ConversionSpeed {
         }
     }
 
-    pub fn to_args(&self, to: &ConverterFormat, gpu: &ConverterGPU, bitrate: u64) -> Vec<String> {
-        let mut args = Vec::new();
+    fn encoder_preset(&self, encoder: &str) -> Option<Vec<String>> {
+        match encoder {
+            // 0 (slowest) to 13 (fastest)
+            "libsvtav1" => {
+                let preset = match self {
+                    ConversionSpeed::UltraFast => "12",
+                    ConversionSpeed::Fast => "10",
+                    ConversionSpeed::Medium => "8",
+                    ConversionSpeed::Slow => "6",
+                    ConversionSpeed::Slower => "4",
+                    ConversionSpeed::VerySlow => "2",
+                };
+                Some(vec!["-preset".to_string(), preset.to_string()])
+            }
 
-        match to {
-            ConverterFormat::MP4
-            | ConverterFormat::MKV
-            | ConverterFormat::MOV
-            | ConverterFormat::MTS
-            | ConverterFormat::TS
-            | ConverterFormat::M2TS => {
-                args.push("-preset".to_string());
-                match gpu {
-                    ConverterGPU::NVIDIA => match self {
-                        // only "slow", "medium", and "fast" are supported
-                        ConversionSpeed::VerySlow | ConversionSpeed::Slower => {
-                            args.push("slow".to_string())
-                        }
-                        ConversionSpeed::Slow | ConversionSpeed::Medium => {
-                            args.push("medium".to_string())
-                        }
-                        ConversionSpeed::Fast | ConversionSpeed::UltraFast => {
-                            args.push("fast".to_string())
-                        }
-                    },
-
-                    _ => match self {
-                        ConversionSpeed::UltraFast => args.push("ultrafast".to_string()),
-                        ConversionSpeed::Fast => args.push("fast".to_string()),
-                        ConversionSpeed::Medium => args.push("medium".to_string()),
-                        ConversionSpeed::Slow => args.push("slow".to_string()),
-                        ConversionSpeed::Slower => args.push("slower".to_string()),
-                        ConversionSpeed::VerySlow => args.push("veryslow".to_string()),
-                    },
-                }
+            // 0 (slowest) to 8 (fastest)
+            "libaom-av1" => {
+                let cpu_used = match self {
+                    ConversionSpeed::UltraFast => "8",
+                    ConversionSpeed::Fast => "6",
+                    ConversionSpeed::Medium => "5",
+                    ConversionSpeed::Slow => "4",
+                    ConversionSpeed::Slower => "2",
+                    ConversionSpeed::VerySlow => "1",
+                };
+                Some(vec![
+                    "-cpu-used".to_string(),
+                    cpu_used.to_string(),
+                    "-row-mt".to_string(),
+                    "1".to_string(),
+                ])
             }
 
-            ConverterFormat::GIF => {}
-
-            ConverterFormat::WebM | ConverterFormat::AVI => {
-                args.push("-speed".to_string());
-                match self {
-                    ConversionSpeed::UltraFast => args.push("4".to_string()),
-                    ConversionSpeed::Fast => args.push("3".to_string()),
-                    ConversionSpeed::Medium => args.push("2".to_string()),
-                    ConversionSpeed::Slow => args.push("1".to_string()),
-                    ConversionSpeed::Slower => args.push("0".to_string()),
-                    ConversionSpeed::VerySlow => args.push("-1".to_string()),
+            // 0 (slowest) to 8 (fastest). row-mt isn't on by default and makes a big difference
+            "libvpx-vp9" => {
+                let speed = match self {
+                    ConversionSpeed::UltraFast => "8",
+                    ConversionSpeed::Fast => "6",
+                    ConversionSpeed::Medium => "4",
+                    ConversionSpeed::Slow => "2",
+                    ConversionSpeed::Slower => "1",
+                    ConversionSpeed::VerySlow => "0",
                 };
+                Some(vec![
+                    "-speed".to_string(),
+                    speed.to_string(),
+                    "-row-mt".to_string(),
+                    "1".to_string(),
+                ])
             }
 
-            ConverterFormat::WMV => {
-                warn!("wmv format does not support speed settings");
+            _ => None,
+        }
+    }
+
+    pub fn to_args(
+        &self,
+        to: &ConverterFormat,
+        gpu: &ConverterGPU,
+        bitrate: u64,
+        encoder: Option<&str>,
+        options: &ConversionOptions,
+    ) -> Vec<String> {
+        let mut args = Vec::new();
+
+        // a few software encoders count their presets differently to x264 and friends
+        if let Some(preset) = encoder.and_then(|encoder| self.encoder_preset(encoder)) {
+            args.extend(preset);
+        } else {
+            match to {
+                ConverterFormat::MP4
+                | ConverterFormat::MKV
+                | ConverterFormat::MOV
+                | ConverterFormat::MTS
+                | ConverterFormat::TS
+                | ConverterFormat::M2TS => {
+                    args.push("-preset".to_string());
+                    match gpu {
+                        ConverterGPU::NVIDIA => match self {
+                            // only "slow", "medium", and "fast" are supported
+                            ConversionSpeed::VerySlow | ConversionSpeed::Slower => {
+                                args.push("slow".to_string())
+                            }
+                            ConversionSpeed::Slow | ConversionSpeed::Medium => {
+                                args.push("medium".to_string())
+                            }
+                            ConversionSpeed::Fast | ConversionSpeed::UltraFast => {
+                                args.push("fast".to_string())
+                            }
+                        },
+
+                        _ => match self {
+                            ConversionSpeed::UltraFast => args.push("ultrafast".to_string()),
+                            ConversionSpeed::Fast => args.push("fast".to_string()),
+                            ConversionSpeed::Medium => args.push("medium".to_string()),
+                            ConversionSpeed::Slow => args.push("slow".to_string()),
+                            ConversionSpeed::Slower => args.push("slower".to_string()),
+                            ConversionSpeed::VerySlow => args.push("veryslow".to_string()),
+                        },
+                    }
+                }
+
+                ConverterFormat::GIF | ConverterFormat::APNG | ConverterFormat::AVIF => {}
+
+                // 0 (fastest) to 6 (smallest)
+                ConverterFormat::WebP => {
+                    args.push("-compression_level".to_string());
+                    match self {
+                        ConversionSpeed::UltraFast => args.push("0".to_string()),
+                        ConversionSpeed::Fast => args.push("2".to_string()),
+                        ConversionSpeed::Medium => args.push("4".to_string()),
+                        ConversionSpeed::Slow => args.push("5".to_string()),
+                        ConversionSpeed::Slower | ConversionSpeed::VerySlow => {
+                            args.push("6".to_string())
+                        }
+                    };
+                }
+
+                ConverterFormat::WebM | ConverterFormat::AVI => {
+                    args.push("-speed".to_string());
+                    match self {
+                        ConversionSpeed::UltraFast => args.push("4".to_string()),
+                        ConversionSpeed::Fast => args.push("3".to_string()),
+                        ConversionSpeed::Medium => args.push("2".to_string()),
+                        ConversionSpeed::Slow => args.push("1".to_string()),
+                        ConversionSpeed::Slower => args.push("0".to_string()),
+                        ConversionSpeed::VerySlow => args.push("-1".to_string()),
+                    };
+                }
+
+                ConverterFormat::WMV => {
+                    warn!("wmv format does not support speed settings");
+                }
+
+                ConverterFormat::MP3
+                | ConverterFormat::AAC
+                | ConverterFormat::M4A
+                | ConverterFormat::Opus
+                | ConverterFormat::OGG => {
+                    // audio bitrates are tiny, so spend a bit more the slower we're allowed to go
+                    let base: f64 = match to {
+                        ConverterFormat::MP3 => 192_000.0,
+                        ConverterFormat::Opus => 128_000.0,
+                        _ => 160_000.0,
+                    };
+                    args.push("-b:a".to_string());
+                    args.push(((base * self.to_bitrate_mul()) as u64).to_string());
+                }
+
+                ConverterFormat::FLAC => {
+                    args.push("-compression_level".to_string());
+                    match self {
+                        ConversionSpeed::UltraFast => args.push("0".to_string()),
+                        ConversionSpeed::Fast => args.push("3".to_string()),
+                        ConversionSpeed::Medium => args.push("5".to_string()),
+                        ConversionSpeed::Slow => args.push("8".to_string()),
+                        ConversionSpeed::Slower => args.push("10".to_string()),
+                        ConversionSpeed::VerySlow => args.push("12".to_string()),
+                    };
+                }
+
+                ConverterFormat::WAV => {}
+            };
+        }
+
+        // animated images pick their own quality
+        if !to.is_animation() && !to.is_audio() {
+            if options.rate_control == RateControl::Size {
+                // `bitrate` is already what it takes to hit the size, so use it as-is
+                args.extend(["-b:v".to_string(), bitrate.to_string()]);
+                // there's no second pass to even things out, so keep it from overshooting
+                if !encoder.is_some_and(codec::supports_two_pass) {
+                    args.extend([
+                        "-maxrate".to_string(),
+                        bitrate.to_string(),
+                        "-bufsize".to_string(),
+                        (bitrate * 2).to_string(),
+                    ]);
+                }
+                args.extend([
+                    "-b:a".to_string(),
+                    codec::TARGET_SIZE_AUDIO_BITRATE.to_string(),
+                ]);
+                return args;
             }
-        };
 
-        if *to != ConverterFormat::GIF {
-            args.push("-b:v".to_string());
             let bitrate = (bitrate as f64 * self.to_bitrate_mul()) as u64;
-            args.push(bitrate.to_string());
+            let quality_args = match options.rate_control {
+                RateControl::Quality => encoder
+                    .and_then(|encoder| codec::quality_args(encoder, options.quality(), bitrate)),
+                RateControl::Bitrate | RateControl::Size => None,
+            };
+
+            match quality_args {
+                Some(quality_args) => args.extend(quality_args),
+                None => {
+                    if options.rate_control == RateControl::Quality {
+                        warn!(
+                            "{} has no constant quality mode, targeting a bitrate instead",
+                            encoder.unwrap_or("this encoder")
+                        );
+                    }
+                    args.push("-b:v".to_string());
+                    args.push(bitrate.to_string());
+                }
+            }
         }
 
         args
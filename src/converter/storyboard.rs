@@ -0,0 +1,253 @@
+use std::time::Instant;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::mpsc};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{
+    job::{self, Job, ProgressUpdate},
+    run_ffmpeg,
+    thumbnail::ThumbnailFormat,
+    ProgressContext,
+};
+
+const DEFAULT_TILES: u32 = 16;
+const DEFAULT_TILE_WIDTH: u32 = 160;
+
+const MAX_TILES: u32 = 100;
+const MIN_TILE_WIDTH: u32 = 16;
+const MAX_TILE_WIDTH: u32 = 640;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoryboardError {
+    #[error("invalid storyboard options: {0}")]
+    InvalidOptions(&'static str),
+}
+
+/// Where a job's WebVTT sprite map goes, next to its sheet.
+pub fn sprite_map_path(job_id: Uuid) -> String {
+    format!("output/{}.vtt", job_id)
+}
+
+/// A grid of evenly spaced frames from the input, e.g. for scrubbing previews.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoryboardOptions {
+    /// How many frames to take. Defaults to 16.
+    pub tiles: Option<u32>,
+    /// Tiles per row. Defaults to as close to a square as it gets.
+    pub columns: Option<u32>,
+    /// Width of each tile in pixels, keeping the aspect ratio. Defaults to 160.
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub format: ThumbnailFormat,
+    /// Also write a WebVTT file mapping each stretch of the input to its tile.
+    #[serde(default)]
+    pub vtt: bool,
+}
+
+impl StoryboardOptions {
+    pub fn validate(&self) -> Result<(), StoryboardError> {
+        if self
+            .tiles
+            .is_some_and(|tiles| !(1..=MAX_TILES).contains(&tiles))
+        {
+            return Err(StoryboardError::InvalidOptions("tiles is out of range"));
+        }
+        let tiles = self.tiles.unwrap_or(DEFAULT_TILES);
+        if self
+            .columns
+            .is_some_and(|columns| !(1..=tiles).contains(&columns))
+        {
+            return Err(StoryboardError::InvalidOptions(
+                "columns must be between 1 and the number of tiles",
+            ));
+        }
+        if self
+            .width
+            .is_some_and(|width| !(MIN_TILE_WIDTH..=MAX_TILE_WIDTH).contains(&width))
+        {
+            return Err(StoryboardError::InvalidOptions("width is out of range"));
+        }
+        Ok(())
+    }
+}
+
+/// `StoryboardOptions` with the defaults filled in.
+pub struct Storyboard {
+    pub tiles: u32,
+    pub columns: u32,
+    pub width: u32,
+    pub format: ThumbnailFormat,
+    pub vtt: bool,
+}
+
+impl Storyboard {
+    pub fn new(options: &StoryboardOptions) -> Self {
+        let tiles = options.tiles.unwrap_or(DEFAULT_TILES);
+        Self {
+            tiles,
+            columns: options
+                .columns
+                .unwrap_or_else(|| (tiles as f64).sqrt().ceil() as u32),
+            width: options.width.unwrap_or(DEFAULT_TILE_WIDTH),
+            format: options.format,
+            vtt: options.vtt,
+        }
+    }
+
+    fn rows(&self) -> u32 {
+        self.tiles.div_ceil(self.columns)
+    }
+
+    /// Spawns ffmpeg to build the sheet, then writes the sprite map next to it if one
+    /// was asked for. Works like `Converter::convert` otherwise.
+    pub async fn generate(
+        &self,
+        job: &mut Job,
+        cancel: CancellationToken,
+    ) -> anyhow::Result<mpsc::Receiver<ProgressUpdate>> {
+        let (tx, rx) = mpsc::channel(1);
+
+        // checked to be positive before the job was started
+        let duration = job.duration().await?;
+        // every tile has to be the same size for the sprite map to line up. the height is
+        // whatever keeps the aspect ratio as it's displayed, i.e. rotated and with square pixels
+        let tile_width = self.width & !1;
+        let rows = self.rows();
+
+        // one frame from the middle of each stretch, rather than the (often black) first frame
+        let interval = duration / self.tiles as f64;
+        let filter = format!(
+            "fps={}/{:.3},scale={}:'max(2,trunc(ow/dar/2)*2)':flags=lanczos,setsar=1,tile={}x{}",
+            self.tiles, duration, tile_width, self.columns, rows
+        );
+
+        let output = format!("output/{}.{}", job.id, self.format.extension());
+        let mut args = vec![
+            "-hide_banner".to_string(),
+            "-loglevel".to_string(),
+            "error".to_string(),
+            "-progress".to_string(),
+            "pipe:1".to_string(),
+            "-ss".to_string(),
+            format!("{:.3}", interval / 2.0),
+            "-i".to_string(),
+            job.input_path(),
+            "-map".to_string(),
+            "0:v:0".to_string(),
+            "-vf".to_string(),
+            filter,
+            "-frames:v".to_string(),
+            "1".to_string(),
+            "-update".to_string(),
+            "1".to_string(),
+        ];
+        args.extend(self.format.encoder_args());
+        args.push(output.clone());
+
+        let sprite_map = self.vtt.then(|| SpriteMap {
+            // the vtt is served from /download/{id}/{token}/vtt, so this is the sheet's
+            // download url, wherever the api happens to be mounted
+            image: format!("../{}", job.auth),
+            tiles: self.tiles,
+            columns: self.columns,
+            tile_width,
+            // read back off the sheet once ffmpeg has picked it
+            tile_height: 0,
+            interval,
+        });
+
+        let job_id = job.id;
+        tokio::spawn(async move {
+            // the sheet only comes out at the very end, so there's nothing to measure against
+            let context = ProgressContext {
+                total_frames: None,
+                duration: None,
+                started: Instant::now(),
+                pass: 0,
+                passes: 1,
+            };
+            match run_ffmpeg(job_id, &args, &tx, &cancel, &context).await {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    error!("{}", e);
+                    let _ = tx.send(ProgressUpdate::Error(e.to_string())).await;
+                    return;
+                }
+            }
+
+            if let Some(mut sprite_map) = sprite_map {
+                let path = sprite_map_path(job_id);
+                info!("writing sprite map for job {}", job_id);
+                let written = async {
+                    let (_, sheet_height) = job::dimensions(&output).await?;
+                    sprite_map.tile_height = sheet_height / rows;
+                    fs::write(&path, sprite_map.to_vtt()).await?;
+                    anyhow::Ok(())
+                }
+                .await;
+                if let Err(e) = written {
+                    error!("failed to write sprite map for job {}: {}", job_id, e);
+                    // the sheet's no good to the client without it
+                    let _ = tx
+                        .send(ProgressUpdate::Error(format!(
+                            "failed to write the sprite map: {}",
+                            e
+                        )))
+                        .await;
+                    fs::remove_file(&output).await.ok();
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+/// WebVTT cues pointing each stretch of the input at its tile in the sheet.
+struct SpriteMap {
+    /// Where the sheet is, relative to the vtt's url.
+    image: String,
+    tiles: u32,
+    columns: u32,
+    tile_width: u32,
+    tile_height: u32,
+    /// Seconds covered by each tile.
+    interval: f64,
+}
+
+impl SpriteMap {
+    fn to_vtt(&self) -> String {
+        let mut vtt = "WEBVTT\n".to_string();
+        for tile in 0..self.tiles {
+            let start = tile as f64 * self.interval;
+            vtt.push_str(&format!(
+                "\n{} --> {}\n{}#xywh={},{},{},{}\n",
+                vtt_timestamp(start),
+                vtt_timestamp(start + self.interval),
+                self.image,
+                tile % self.columns * self.tile_width,
+                tile / self.columns * self.tile_height,
+                self.tile_width,
+                self.tile_height
+            ));
+        }
+        vtt
+    }
+}
+
+/// e.g. 3725.5 -> "01:02:05.500"
+fn vtt_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
@@ -0,0 +1,200 @@
+use std::{process::Stdio, time::Duration};
+
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{process::Command, sync::Semaphore};
+use uuid::Uuid;
+
+use crate::state::APP_STATE;
+
+/// Thumbnails skip the conversion queue, so they get their own, smaller limit.
+const MAX_CONCURRENT_THUMBNAILS: usize = 4;
+/// A single frame should never take this long, even from a huge file.
+const THUMBNAIL_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_WIDTH: u32 = 4096;
+/// Where the automatic pick starts looking, as a fraction of the length. The very start
+/// is often a black frame or a title card.
+const AUTO_SEEK: f64 = 0.1;
+
+lazy_static! {
+    static ref THUMBNAIL_SLOTS: Semaphore = Semaphore::new(MAX_CONCURRENT_THUMBNAILS);
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl ThumbnailFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Png => "image/png",
+            ThumbnailFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+
+    pub fn encoder_args(&self) -> Vec<String> {
+        let args: &[&str] = match self {
+            ThumbnailFormat::Jpeg => &["-c:v", "mjpeg", "-q:v", "3"],
+            ThumbnailFormat::Png => &["-c:v", "png"],
+            ThumbnailFormat::Webp => &["-c:v", "libwebp", "-quality", "80"],
+        };
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+}
+
+/// Scales down to `width`, keeping the aspect ratio. Never upscales.
+pub fn scale_filter(width: u32) -> String {
+    format!("scale='min({},iw)':-1:flags=lanczos", width)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ThumbnailOptions {
+    /// Seconds into the input. Without it, ffmpeg's `thumbnail` filter picks a
+    /// representative frame.
+    pub at: Option<f64>,
+    pub format: ThumbnailFormat,
+    /// See `scale_filter`.
+    pub width: Option<u32>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThumbnailError {
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("the upload has already been converted")]
+    InputGone,
+    #[error("the input has no video to take a frame from")]
+    NoVideo,
+    #[error("the timestamp has to be at least 0 and under {0:.2} seconds")]
+    InvalidTimestamp(f64),
+    #[error("width must be between 2 and {}", MAX_WIDTH)]
+    InvalidWidth,
+    #[error("failed to extract a frame: {0}")]
+    Failed(#[from] anyhow::Error),
+}
+
+pub struct Thumbnail {
+    pub format: ThumbnailFormat,
+    pub data: Vec<u8>,
+}
+
+/// Grabs a single frame from an uploaded job. This leaves the job as it is, so it can
+/// be called any time between the upload and the conversion finishing.
+pub async fn extract(
+    job_id: Uuid,
+    token: &str,
+    options: ThumbnailOptions,
+) -> Result<Thumbnail, ThumbnailError> {
+    let mut job = {
+        let app_state = APP_STATE.lock().await;
+        app_state.get_job(&job_id).cloned()
+    }
+    .ok_or(ThumbnailError::JobNotFound)?;
+
+    if job.auth != token {
+        return Err(ThumbnailError::InvalidToken);
+    }
+    // the input is removed once the conversion is done with it
+    if job.status.is_finished() {
+        return Err(ThumbnailError::InputGone);
+    }
+    if options
+        .width
+        .is_some_and(|width| !(2..=MAX_WIDTH).contains(&width))
+    {
+        return Err(ThumbnailError::InvalidWidth);
+    }
+    if !job.has_video().await? {
+        return Err(ThumbnailError::NoVideo);
+    }
+
+    let mut filters = Vec::new();
+    let seek = match options.at {
+        Some(at) => {
+            let duration = job.duration().await?;
+            // there's no frame left to show at the very end
+            if !(0.0..duration).contains(&at) {
+                return Err(ThumbnailError::InvalidTimestamp(duration));
+            }
+            at
+        }
+        None => {
+            filters.push("thumbnail".to_string());
+            // not knowing the length just means looking from the start
+            job.duration()
+                .await
+                .map_or(0.0, |length| length * AUTO_SEEK)
+        }
+    };
+    if let Some(width) = options.width {
+        filters.push(scale_filter(width));
+    }
+
+    let mut args = vec![
+        "-hide_banner".to_string(),
+        "-loglevel".to_string(),
+        "error".to_string(),
+        "-ss".to_string(),
+        format!("{:.3}", seek),
+        "-i".to_string(),
+        job.input_path(),
+        "-map".to_string(),
+        "0:v:0".to_string(),
+        "-frames:v".to_string(),
+        "1".to_string(),
+    ];
+    if !filters.is_empty() {
+        args.extend(["-vf".to_string(), filters.join(",")]);
+    }
+    args.extend(options.format.encoder_args());
+    args.extend(["-f", "image2pipe", "pipe:1"].map(String::from));
+
+    // wait in line without holding up anyone else's request
+    let _permit = THUMBNAIL_SLOTS
+        .acquire()
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    info!("running 'ffmpeg {}'", args.join(" "));
+    // if the client hangs up, the request gets dropped and takes ffmpeg down with it
+    let output = Command::new("ffmpeg")
+        .args(&args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(THUMBNAIL_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow::anyhow!("ffmpeg timed out"))?
+        .map_err(|e| anyhow::anyhow!("failed to spawn ffmpeg: {}", e))?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("thumbnail for job {} failed: {}", job_id, stderr.trim());
+        return Err(ThumbnailError::Failed(anyhow::anyhow!(
+            "{}",
+            stderr.lines().last().unwrap_or("no frame was produced")
+        )));
+    }
+
+    Ok(Thumbnail {
+        format: options.format,
+        data: output.stdout,
+    })
+}
@@ -1,12 +1,22 @@
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
 use log::info;
-use services::{download::download, upload::upload, version::version, websocket::websocket};
+use services::{
+    download::{download, download_sprite_map},
+    job::{
+        job_events, job_status, job_thumbnail, start_compression, start_frame_export, start_job,
+        start_sequence, start_storyboard,
+    },
+    tus::{tus_create, tus_offset, tus_options, tus_patch, tus_terminate},
+    upload::{upload, upload_compression, upload_sequence},
+    version::version,
+    websocket::websocket,
+};
 use crate::http::auth::Authentication;
 
 mod auth;
 mod response;
-mod services;
+pub mod services;
 
 pub async fn start_http(auth_token: String) -> anyhow::Result<()> {
     let server = HttpServer::new(move || {
@@ -15,7 +25,9 @@ pub async fn start_http(auth_token: String) -> anyhow::Result<()> {
                 Cors::default()
                     .allow_any_origin()
                     .allow_any_method()
-                    .allow_any_header(),
+                    .allow_any_header()
+                    // tus clients need to read Location, Upload-Offset and friends
+                    .expose_any_header(),
             )
             .app_data(web::Data::new(auth_token.clone()))
             .service(
@@ -26,7 +38,23 @@ pub async fn start_http(auth_token: String) -> anyhow::Result<()> {
                         web::scope("") // Create a sub-scope for auth
                             .wrap(Authentication)
                             .service(upload)
+                            .service(upload_compression)
+                            .service(upload_sequence)
+                            .service(tus_options)
+                            .service(tus_create)
+                            .service(tus_offset)
+                            .service(tus_patch)
+                            .service(tus_terminate)
                             .service(download)
+                            .service(download_sprite_map)
+                            .service(job_events) // before job_status, which would match it too
+                            .service(job_thumbnail) // same here
+                            .service(job_status)
+                            .service(start_job)
+                            .service(start_compression)
+                            .service(start_sequence)
+                            .service(start_storyboard)
+                            .service(start_frame_export)
                             .service(websocket),
                     )
             )
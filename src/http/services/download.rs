@@ -1,10 +1,52 @@
-// get /download/{id} where id is Uuid
+// get /download/{id}/{token} and get /download/{id}/{token}/vtt where id is Uuid
 
-use actix_web::{get, web, HttpResponse, Responder, ResponseError};
+use std::{
+    env,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use actix_files::NamedFile;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    get,
+    http::{
+        header::{Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue},
+        Method, StatusCode,
+    },
+    route,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use lazy_static::lazy_static;
+use log::{info, warn};
 use tokio::fs;
 use uuid::Uuid;
 
-use crate::{http::response::ApiResponse, state::APP_STATE};
+use crate::{
+    converter::{job::JobStatus, storyboard},
+    http::response::ApiResponse,
+    state::{self, APP_STATE},
+};
+
+lazy_static! {
+    /// Whether outputs are deleted as soon as they've been downloaded in full, rather than
+    /// kept until they expire. Read from `VERTD_DELETE_ON_DOWNLOAD` (defaults to true).
+    static ref DELETE_ON_DOWNLOAD: bool = match env::var("VERTD_DELETE_ON_DOWNLOAD") {
+        Ok(value) => match value.to_lowercase().as_str() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => {
+                warn!(
+                    "invalid value for VERTD_DELETE_ON_DOWNLOAD: '{}'. using true.",
+                    value
+                );
+                true
+            }
+        },
+        Err(_) => true,
+    };
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum DownloadError {
@@ -14,6 +56,10 @@ pub enum DownloadError {
     IncompleteHandshake,
     #[error("invalid token")]
     InvalidToken,
+    #[error("job has not finished yet")]
+    NotFinished,
+    #[error("job has no sprite map")]
+    NoSpriteMap,
     #[error("filesystem error: {0}")]
     FilesystemError(#[from] std::io::Error),
 }
@@ -21,9 +67,12 @@ pub enum DownloadError {
 impl ResponseError for DownloadError {
     fn error_response(&self) -> HttpResponse {
         let status = match self {
-            DownloadError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            DownloadError::JobNotFound | DownloadError::NoSpriteMap => {
+                actix_web::http::StatusCode::NOT_FOUND
+            }
             DownloadError::IncompleteHandshake => actix_web::http::StatusCode::BAD_REQUEST,
             DownloadError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            DownloadError::NotFinished => actix_web::http::StatusCode::CONFLICT,
             DownloadError::FilesystemError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -31,13 +80,16 @@ impl ResponseError for DownloadError {
     }
 }
 
-#[get("/download/{id}/{token}")]
-pub async fn download(path: web::Path<(Uuid, String)>) -> Result<impl Responder, DownloadError> {
+/// Serves the output with range, conditional and HEAD support, named after the uploaded file.
+#[route("/download/{id}/{token}", method = "GET", method = "HEAD")]
+pub async fn download(
+    req: HttpRequest,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, DownloadError> {
     let (id, token) = path.into_inner();
     let app_state = APP_STATE.lock().await;
     let job = app_state
-        .jobs
-        .get(&id)
+        .get_job(&id)
         .ok_or(DownloadError::JobNotFound)?
         .clone();
     drop(app_state);
@@ -46,16 +98,13 @@ pub async fn download(path: web::Path<(Uuid, String)>) -> Result<impl Responder,
         return Err(DownloadError::InvalidToken);
     }
 
-    let file_path = match job.to {
-        Some(to) => format!("output/{}.{}", id, to),
-        None => return Err(DownloadError::IncompleteHandshake),
-    };
-
-    let mut app_state = APP_STATE.lock().await;
-    app_state.jobs.remove(&id);
-    drop(app_state);
+    let to = job.to.clone().ok_or(DownloadError::IncompleteHandshake)?;
+    if job.status != JobStatus::Completed {
+        return Err(DownloadError::NotFinished);
+    }
 
-    let bytes = fs::read(&file_path).await.map_err(|e| {
+    let file_path = format!("output/{}.{}", id, to);
+    let file = NamedFile::open_async(&file_path).await.map_err(|e| {
         if e.kind() == std::io::ErrorKind::NotFound {
             DownloadError::JobNotFound
         } else {
@@ -63,16 +112,127 @@ pub async fn download(path: web::Path<(Uuid, String)>) -> Result<impl Responder,
         }
     })?;
 
-    let mime = mime_guess::from_path(&file_path)
-        .first_or_octet_stream()
-        .to_string();
+    let response = file
+        .set_content_disposition(attachment(&job.output_name(&to)))
+        .into_response(&req);
 
-    fs::remove_file(file_path)
+    // only a whole-file download counts -- range requests are just browsers seeking around.
+    // storyboard sheets get fetched over and over by whatever plays their sprite map
+    let has_sprite_map = fs::try_exists(storyboard::sprite_map_path(id))
         .await
-        .map_err(|e| DownloadError::FilesystemError(e))?;
+        .unwrap_or(false);
+    if !*DELETE_ON_DOWNLOAD
+        || has_sprite_map
+        || req.method() != Method::GET
+        || response.status() != StatusCode::OK
+    {
+        return Ok(response);
+    }
+
+    Ok(response
+        .map_body(|_, body| OnComplete {
+            body,
+            on_complete: Some(Box::new(move || {
+                tokio::spawn(async move {
+                    info!("job {} downloaded, removing its output", id);
+                    let mut app_state = APP_STATE.lock().await;
+                    app_state.remove_job(&id);
+                    drop(app_state);
+                    state::remove_output(id, &to).await;
+                });
+            })),
+        })
+        .map_into_boxed_body())
+}
+
+/// Serves the WebVTT sprite map of a storyboard. Downloading doesn't delete either it or
+/// the sheet, so players can keep fetching both until they expire.
+#[get("/download/{id}/{token}/vtt")]
+pub async fn download_sprite_map(
+    req: HttpRequest,
+    path: web::Path<(Uuid, String)>,
+) -> Result<HttpResponse, DownloadError> {
+    let (id, token) = path.into_inner();
+    let app_state = APP_STATE.lock().await;
+    let job = app_state
+        .get_job(&id)
+        .ok_or(DownloadError::JobNotFound)?
+        .clone();
+    drop(app_state);
 
-    Ok(HttpResponse::Ok()
-        .insert_header(("Content-Type", mime))
-        .insert_header(("Content-Length", bytes.len()))
-        .body(bytes))
+    if job.auth != token {
+        return Err(DownloadError::InvalidToken);
+    }
+    if job.status != JobStatus::Completed {
+        return Err(DownloadError::NotFinished);
+    }
+
+    let file = NamedFile::open_async(storyboard::sprite_map_path(id))
+        .await
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                DownloadError::NoSpriteMap
+            } else {
+                DownloadError::FilesystemError(e)
+            }
+        })?;
+
+    Ok(file
+        .set_content_type("text/vtt".parse().unwrap())
+        .set_content_disposition(attachment(&job.output_name("vtt")))
+        .into_response(&req))
+}
+
+fn attachment(name: &str) -> ContentDisposition {
+    // plain ascii for old clients, the real name for everyone who understands filename*
+    let ascii = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(ascii)];
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: name.as_bytes().to_vec(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Runs `on_complete` once the whole body has been sent, but not if the client hangs up first.
+struct OnComplete<B> {
+    body: B,
+    on_complete: Option<Box<dyn FnOnce()>>,
+}
+
+impl<B: MessageBody + Unpin> MessageBody for OnComplete<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let next = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(None) = next {
+            if let Some(on_complete) = self.on_complete.take() {
+                on_complete();
+            }
+        }
+        next
+    }
 }
@@ -0,0 +1,388 @@
+// get /job/{id}/{token}, get /job/{id}/events, get /job/{id}/thumbnail,
+// post /job/{id}/start, post /job/{id}/compress, post /job/{id}/sequence,
+// post /job/{id}/storyboard and post /job/{id}/frames where id is Uuid
+
+use std::time::Duration;
+
+use actix_web::{get, post, web, web::Bytes, HttpResponse, Responder, ResponseError};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::{
+    converter::{
+        codec::ConversionOptions,
+        frames::FrameExportOptions,
+        job::{JobKind, JobProgress, JobStatus},
+        queue::QUEUE,
+        runner::{self, StartError},
+        speed::ConversionSpeed,
+        storyboard::StoryboardOptions,
+        thumbnail::{self, ThumbnailError, ThumbnailFormat, ThumbnailOptions},
+    },
+    http::{response::ApiResponse, services::websocket::Message},
+    state::{AppState, APP_STATE},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum JobError {
+    #[error("job not found")]
+    JobNotFound,
+    #[error("invalid token")]
+    InvalidToken,
+}
+
+impl ResponseError for JobError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            JobError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            JobError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+        };
+
+        HttpResponse::build(status).json(ApiResponse::<()>::Error(self.to_string()))
+    }
+}
+
+impl ResponseError for StartError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            StartError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            StartError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            StartError::InvalidInputFormat
+            | StartError::InvalidOutputFormat
+            | StartError::AudioToVideo
+            | StartError::WrongKind(_)
+            | StartError::InvalidReduction
+            | StartError::InvalidFrameRate
+            | StartError::NoVideo
+            | StartError::UnknownLength
+            | StartError::TargetTooSmall { .. }
+            | StartError::UnsupportedCodec(_)
+            | StartError::InvalidStoryboard(_)
+            | StartError::InvalidFrameExport(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            StartError::AlreadyCompleted | StartError::AlreadyRunning => {
+                actix_web::http::StatusCode::CONFLICT
+            }
+            StartError::ReadInput(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        HttpResponse::build(status).json(ApiResponse::<()>::Error(self.to_string()))
+    }
+}
+
+impl ResponseError for ThumbnailError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            ThumbnailError::JobNotFound => actix_web::http::StatusCode::NOT_FOUND,
+            ThumbnailError::InvalidToken => actix_web::http::StatusCode::UNAUTHORIZED,
+            ThumbnailError::InputGone => actix_web::http::StatusCode::CONFLICT,
+            ThumbnailError::NoVideo
+            | ThumbnailError::InvalidTimestamp(_)
+            | ThumbnailError::InvalidWidth => actix_web::http::StatusCode::BAD_REQUEST,
+            ThumbnailError::Failed(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        HttpResponse::build(status).json(ApiResponse::<()>::Error(self.to_string()))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobState {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub from: String,
+    pub to: Option<String>,
+    pub queue_position: Option<usize>,
+    #[serde(flatten)]
+    pub progress: JobProgress,
+}
+
+impl JobState {
+    fn of(app_state: &AppState, id: Uuid) -> Option<Self> {
+        let job = app_state.get_job(&id)?;
+        Some(Self {
+            id,
+            kind: job.kind,
+            status: job.status,
+            from: job.from.clone(),
+            to: job.to.clone(),
+            queue_position: QUEUE.position(&id),
+            progress: app_state.progress.get(&id).cloned().unwrap_or_default(),
+        })
+    }
+}
+
+// proxies tend to drop connections that have been quiet for a while
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    pub token: String,
+    /// Seconds into the input, otherwise a representative frame gets picked.
+    pub at: Option<f64>,
+    #[serde(default)]
+    pub format: ThumbnailFormat,
+    pub width: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartJobRequest {
+    pub token: String,
+    pub to: String,
+    pub speed: ConversionSpeed,
+    #[serde(flatten)]
+    pub options: ConversionOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartCompressionRequest {
+    pub token: String,
+    /// How much smaller the output should be, in percent.
+    pub reduction: u8,
+    pub speed: ConversionSpeed,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartSequenceRequest {
+    pub token: String,
+    pub to: String,
+    /// How many of the images make up a second of video.
+    pub fps: u32,
+    pub speed: ConversionSpeed,
+    #[serde(flatten)]
+    pub options: ConversionOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartStoryboardRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub options: StoryboardOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartFrameExportRequest {
+    pub token: String,
+    #[serde(flatten)]
+    pub options: FrameExportOptions,
+}
+
+#[get("/job/{id}/{token}")]
+pub async fn job_status(path: web::Path<(Uuid, String)>) -> Result<impl Responder, JobError> {
+    let (id, token) = path.into_inner();
+    let app_state = APP_STATE.lock().await;
+    let job = app_state.get_job(&id).ok_or(JobError::JobNotFound)?;
+
+    if job.auth != token {
+        return Err(JobError::InvalidToken);
+    }
+
+    let state = JobState::of(&app_state, id).ok_or(JobError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
+
+/// Streams the job's progress as server-sent events, using the same messages as the
+/// websocket. Late subscribers first get a snapshot of where the job is at, and the
+/// stream ends once the job is finished or cancelled.
+#[get("/job/{id}/events")]
+pub async fn job_events(
+    path: web::Path<Uuid>,
+    query: web::Query<EventsQuery>,
+) -> Result<HttpResponse, JobError> {
+    let id = path.into_inner();
+    let mut app_state = APP_STATE.lock().await;
+    let job = app_state.get_job(&id).ok_or(JobError::JobNotFound)?;
+
+    if job.auth != query.token {
+        return Err(JobError::InvalidToken);
+    }
+    let status = job.status;
+
+    // subscribe while still holding the lock so nothing slips between the snapshot and the feed
+    let progress = app_state.progress.get(&id).cloned().unwrap_or_default();
+    let (snapshot, events) = match status {
+        // not started yet -- wait around for whoever starts it
+        JobStatus::Uploaded => (vec![], Some(app_state.job_events(id).subscribe())),
+        JobStatus::Queued | JobStatus::Running => {
+            let mut snapshot = Vec::new();
+            if status == JobStatus::Running {
+                snapshot.push(Message::JobStarted { job_id: id });
+            }
+            snapshot.extend(QUEUE.position(&id).map(|position| Message::QueuePosition {
+                job_id: id,
+                position,
+            }));
+            snapshot.extend(progress.updates().into_iter().map(Message::ProgressUpdate));
+            (snapshot, app_state.subscribe_events(&id))
+        }
+        JobStatus::Completed => (vec![Message::JobFinished { job_id: id }], None),
+        JobStatus::Failed => (
+            vec![Message::Error {
+                message: progress
+                    .error
+                    .unwrap_or_else(|| "conversion failed".to_string()),
+            }],
+            None,
+        ),
+        JobStatus::Cancelled => (vec![Message::JobCancelled { job_id: id }], None),
+    };
+    drop(app_state);
+
+    let snapshot = stream::iter(
+        snapshot
+            .into_iter()
+            .map(|message| Ok::<_, actix_web::Error>(sse_event(message))),
+    );
+    let live = stream::unfold(events, |events| async move {
+        let mut events = events?;
+        loop {
+            match tokio::time::timeout(KEEP_ALIVE_INTERVAL, events.recv()).await {
+                Ok(Ok(message)) => return Some((Ok(sse_event(message)), Some(events))),
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), Some(events))),
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(futures_util::StreamExt::chain(snapshot, live)))
+}
+
+/// A single frame of the upload as an image, e.g. for a preview before it's converted.
+#[get("/job/{id}/thumbnail")]
+pub async fn job_thumbnail(
+    path: web::Path<Uuid>,
+    query: web::Query<ThumbnailQuery>,
+) -> Result<HttpResponse, ThumbnailError> {
+    let ThumbnailQuery {
+        token,
+        at,
+        format,
+        width,
+    } = query.into_inner();
+    let options = ThumbnailOptions { at, format, width };
+    let thumbnail = thumbnail::extract(path.into_inner(), &token, options).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(thumbnail.format.content_type())
+        .insert_header(("Cache-Control", "private, max-age=3600"))
+        .body(thumbnail.data))
+}
+
+/// `event: <message type>` followed by the message as json, same as the websocket sends it.
+fn sse_event(message: Message) -> Bytes {
+    let kind = serde_json::to_value(&message).unwrap()["type"]
+        .as_str()
+        .unwrap_or("message")
+        .to_string();
+    let data: String = message.into();
+    Bytes::from(format!("event: {}\ndata: {}\n\n", kind, data))
+}
+
+/// Starts the conversion without anyone listening -- poll `job_status` for progress.
+#[post("/job/{id}/start")]
+pub async fn start_job(
+    path: web::Path<Uuid>,
+    body: web::Json<StartJobRequest>,
+) -> Result<impl Responder, StartError> {
+    let id = path.into_inner();
+    let StartJobRequest {
+        token,
+        to,
+        speed,
+        options,
+    } = body.into_inner();
+    runner::start_job(id, &token, &to, speed, options).await?;
+
+    let app_state = APP_STATE.lock().await;
+    let state = JobState::of(&app_state, id).ok_or(StartError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
+
+/// Starts compressing a job uploaded through `/upload/compress`, same as `start_job`.
+#[post("/job/{id}/compress")]
+pub async fn start_compression(
+    path: web::Path<Uuid>,
+    body: web::Json<StartCompressionRequest>,
+) -> Result<impl Responder, StartError> {
+    let id = path.into_inner();
+    let StartCompressionRequest {
+        token,
+        reduction,
+        speed,
+    } = body.into_inner();
+    runner::start_compression(id, &token, reduction, speed).await?;
+
+    let app_state = APP_STATE.lock().await;
+    let state = JobState::of(&app_state, id).ok_or(StartError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
+
+/// Starts encoding a job uploaded through `/upload/sequence`, same as `start_job`.
+#[post("/job/{id}/sequence")]
+pub async fn start_sequence(
+    path: web::Path<Uuid>,
+    body: web::Json<StartSequenceRequest>,
+) -> Result<impl Responder, StartError> {
+    let id = path.into_inner();
+    let StartSequenceRequest {
+        token,
+        to,
+        fps,
+        speed,
+        options,
+    } = body.into_inner();
+    runner::start_sequence(id, &token, &to, fps, speed, options).await?;
+
+    let app_state = APP_STATE.lock().await;
+    let state = JobState::of(&app_state, id).ok_or(StartError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
+
+/// Starts a storyboard of a regular upload, same as `start_job`. The sheet downloads like
+/// any other output, and the sprite map (if asked for) from `/download/{id}/{token}/vtt`.
+#[post("/job/{id}/storyboard")]
+pub async fn start_storyboard(
+    path: web::Path<Uuid>,
+    body: web::Json<StartStoryboardRequest>,
+) -> Result<impl Responder, StartError> {
+    let id = path.into_inner();
+    let StartStoryboardRequest { token, options } = body.into_inner();
+    runner::start_storyboard(id, &token, options).await?;
+
+    let app_state = APP_STATE.lock().await;
+    let state = JobState::of(&app_state, id).ok_or(StartError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
+
+/// Starts exporting the frames of a regular upload, same as `start_job`. The zip
+/// downloads like any other output.
+#[post("/job/{id}/frames")]
+pub async fn start_frame_export(
+    path: web::Path<Uuid>,
+    body: web::Json<StartFrameExportRequest>,
+) -> Result<impl Responder, StartError> {
+    let id = path.into_inner();
+    let StartFrameExportRequest { token, options } = body.into_inner();
+    runner::start_frame_export(id, &token, options).await?;
+
+    let app_state = APP_STATE.lock().await;
+    let state = JobState::of(&app_state, id).ok_or(StartError::JobNotFound)?;
+    Ok(ApiResponse::Success(state))
+}
@@ -1,4 +1,6 @@
 pub mod download;
+pub mod job;
+pub mod tus;
 pub mod upload;
 pub mod version;
 pub mod websocket;
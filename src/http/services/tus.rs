@@ -0,0 +1,392 @@
+// tus 1.0 resumable uploads (https://tus.io/protocols/resumable-upload) under /tus
+// supports the core protocol plus the creation and termination extensions
+
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use actix_web::{
+    delete, head, http::StatusCode, patch, post, route, web, HttpRequest, HttpResponse,
+    HttpResponseBuilder, ResponseError,
+};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
+use futures_util::StreamExt as _;
+use lazy_static::lazy_static;
+use log::{error, info};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+use uuid::Uuid;
+
+use super::upload::{
+    check_kind, input_extension, job_token, register_job, UploadError, MAX_UPLOAD_SIZE,
+};
+use crate::{
+    converter::job::{Job, JobKind},
+    http::response::ApiResponse,
+    state::APP_STATE,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
+
+lazy_static! {
+    /// Uploads that haven't received all of their bytes yet.
+    static ref UPLOADS: Mutex<HashMap<Uuid, TusUpload>> = Mutex::new(HashMap::new());
+}
+
+struct TusUpload {
+    length: u64,
+    offset: u64,
+    ext: String,
+    filename: String,
+    kind: JobKind,
+    /// A PATCH is currently writing to this upload.
+    busy: bool,
+    /// When the upload last received any bytes, so only abandoned ones expire.
+    last_activity: Instant,
+}
+
+fn partial_path(id: &Uuid, ext: &str) -> String {
+    format!("input/{}.{}.part", id, ext)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TusError {
+    #[error("unsupported tus version (expected {})", TUS_VERSION)]
+    UnsupportedVersion,
+    #[error("missing or invalid Upload-Length")]
+    InvalidLength,
+    #[error("missing or invalid Upload-Offset")]
+    InvalidOffset,
+    #[error("invalid Upload-Metadata")]
+    InvalidMetadata,
+    #[error("Upload-Metadata must include a filename")]
+    NoFilename,
+    #[error("content type must be application/offset+octet-stream")]
+    InvalidContentType,
+    #[error("upload not found")]
+    UploadNotFound,
+    #[error("offset does not match, upload is at {0}")]
+    OffsetMismatch(u64),
+    #[error("upload is already being written to")]
+    Busy,
+    #[error("data goes past the end of the upload")]
+    ExceedsLength,
+    #[error("failed to read upload data")]
+    GetChunk(#[from] actix_web::error::PayloadError),
+    #[error("internal server error while writing file")]
+    WriteFile(#[from] std::io::Error),
+    #[error(transparent)]
+    Upload(#[from] UploadError),
+}
+
+impl ResponseError for TusError {
+    fn error_response(&self) -> HttpResponse {
+        let status = match self {
+            TusError::UnsupportedVersion => StatusCode::PRECONDITION_FAILED,
+            TusError::InvalidContentType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            TusError::UploadNotFound => StatusCode::NOT_FOUND,
+            TusError::OffsetMismatch(_) => StatusCode::CONFLICT,
+            TusError::Busy => StatusCode::LOCKED,
+            TusError::WriteFile(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            TusError::Upload(e) => {
+                let mut response = e.error_response();
+                response.headers_mut().insert(
+                    "Tus-Resumable".try_into().unwrap(),
+                    TUS_VERSION.try_into().unwrap(),
+                );
+                return response;
+            }
+            _ => StatusCode::BAD_REQUEST,
+        };
+
+        let mut response = tus_response(status);
+        if let TusError::UnsupportedVersion = self {
+            response.insert_header(("Tus-Version", TUS_VERSION));
+        }
+        response.json(ApiResponse::<()>::Error(self.to_string()))
+    }
+}
+
+/// Every tus response has to say which version of the protocol it speaks.
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut response = HttpResponse::build(status);
+    response.insert_header(("Tus-Resumable", TUS_VERSION));
+    response
+}
+
+fn check_version(req: &HttpRequest) -> Result<(), TusError> {
+    match req.headers().get("Tus-Resumable") {
+        Some(version) if version == TUS_VERSION => Ok(()),
+        _ => Err(TusError::UnsupportedVersion),
+    }
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Option<u64> {
+    req.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// `key base64value,key2 base64value2` -- values are optional.
+fn parse_metadata(header: &str) -> Result<HashMap<String, String>, TusError> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = BASE64_STANDARD
+                .decode(value.trim())
+                .map_err(|_| TusError::InvalidMetadata)?;
+            let value = String::from_utf8(value).map_err(|_| TusError::InvalidMetadata)?;
+            Ok((key.to_string(), value))
+        })
+        .collect()
+}
+
+#[route("/tus", method = "OPTIONS")]
+pub async fn tus_options() -> HttpResponse {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", MAX_UPLOAD_SIZE.to_string()))
+        .finish()
+}
+
+#[post("/tus")]
+pub async fn tus_create(req: HttpRequest) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+
+    let length = header_u64(&req, "Upload-Length").ok_or(TusError::InvalidLength)?;
+    if length > *MAX_UPLOAD_SIZE {
+        return Err(UploadError::TooLarge(*MAX_UPLOAD_SIZE).into());
+    }
+
+    let metadata = match req.headers().get("Upload-Metadata") {
+        Some(header) => parse_metadata(header.to_str().map_err(|_| TusError::InvalidMetadata)?)?,
+        None => HashMap::new(),
+    };
+    let filename = metadata.get("filename").ok_or(TusError::NoFilename)?;
+    let ext = input_extension(filename)?;
+    // "compression" makes a job for `start_compression`, otherwise it's a conversion
+    let kind = match metadata.get("kind") {
+        Some(kind) => kind.parse().map_err(|_| TusError::InvalidMetadata)?,
+        None => JobKind::Conversion,
+    };
+    // sequences are many files, which only `/upload/sequence` takes
+    if kind == JobKind::ImageSequence {
+        return Err(TusError::InvalidMetadata);
+    }
+    check_kind(&ext, kind)?;
+
+    let id = Uuid::new_v4();
+    fs::File::create(partial_path(&id, &ext)).await?;
+    UPLOADS.lock().unwrap().insert(
+        id,
+        TusUpload {
+            length,
+            offset: 0,
+            ext,
+            filename: filename.clone(),
+            kind,
+            busy: false,
+            last_activity: Instant::now(),
+        },
+    );
+    info!(
+        "tus upload {} created for {} ({} bytes)",
+        id, filename, length
+    );
+
+    expire_upload(id);
+
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), id);
+    Ok(tus_response(StatusCode::CREATED)
+        .insert_header(("Location", location))
+        .finish())
+}
+
+/// Reports how far along the upload is. Finished uploads are reported as complete, but
+/// never with their job's token, since the id is no secret.
+#[head("/tus/{id}")]
+pub async fn tus_offset(req: HttpRequest, path: web::Path<Uuid>) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let id = path.into_inner();
+
+    let in_progress = UPLOADS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|upload| (upload.offset, upload.length));
+    if let Some((offset, length)) = in_progress {
+        return Ok(tus_response(StatusCode::OK)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .insert_header(("Upload-Length", length.to_string()))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish());
+    }
+
+    let job = {
+        let app_state = APP_STATE.lock().await;
+        app_state.get_job(&id).cloned()
+    }
+    .ok_or(TusError::UploadNotFound)?;
+    let length = fs::metadata(job.input_location())
+        .await
+        .map_err(|_| TusError::UploadNotFound)?
+        .len();
+
+    Ok(tus_response(StatusCode::OK)
+        .insert_header(("Upload-Offset", length.to_string()))
+        .insert_header(("Upload-Length", length.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+/// Appends the request body to the upload. The PATCH that completes the upload responds
+/// with the job it turned into, like `/upload` does, and carries its token in
+/// `Vertd-Job-Token` too; the job id is the upload id.
+#[patch("/tus/{id}")]
+pub async fn tus_patch(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let id = path.into_inner();
+
+    if req.headers().get("Content-Type").map(|v| v.as_bytes())
+        != Some(b"application/offset+octet-stream")
+    {
+        return Err(TusError::InvalidContentType);
+    }
+    let mut offset = header_u64(&req, "Upload-Offset").ok_or(TusError::InvalidOffset)?;
+
+    let (length, ext, filename, kind) = {
+        let mut uploads = UPLOADS.lock().unwrap();
+        let upload = uploads.get_mut(&id).ok_or(TusError::UploadNotFound)?;
+        if upload.busy {
+            return Err(TusError::Busy);
+        }
+        if upload.offset != offset {
+            return Err(TusError::OffsetMismatch(upload.offset));
+        }
+        upload.busy = true;
+        (
+            upload.length,
+            upload.ext.clone(),
+            upload.filename.clone(),
+            upload.kind,
+        )
+    };
+    let _busy = BusyGuard(id);
+
+    let path = partial_path(&id, &ext);
+    let file = OpenOptions::new().write(true).open(&path).await?;
+    // drop anything written past the offset we last recorded
+    file.set_len(offset).await?;
+    let mut file = OpenOptions::new().append(true).open(&path).await?;
+
+    while let Some(chunk) = payload.next().await {
+        let data = chunk?;
+        if offset + data.len() as u64 > length {
+            return Err(TusError::ExceedsLength);
+        }
+        file.write_all(&data).await?;
+        offset += data.len() as u64;
+        // record progress as we go so an interrupted PATCH can be resumed from here
+        if let Some(upload) = UPLOADS.lock().unwrap().get_mut(&id) {
+            upload.offset = offset;
+            upload.last_activity = Instant::now();
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    if offset < length {
+        return Ok(tus_response(StatusCode::NO_CONTENT)
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .finish());
+    }
+
+    UPLOADS.lock().unwrap().remove(&id);
+    fs::rename(&path, format!("input/{}.{}", id, ext)).await?;
+    info!("tus upload {} finished", id);
+
+    let mut job = Job::with_id(id, job_token(), ext);
+    job.filename = Some(filename);
+    job.kind = kind;
+    let job = register_job(job).await?;
+
+    Ok(tus_response(StatusCode::OK)
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .insert_header(("Vertd-Job-Token", job.auth.clone()))
+        .json(ApiResponse::Success(job)))
+}
+
+/// Gives up on the upload once it's gone `INPUT_LIFETIME` without receiving anything.
+/// Slow uploads that keep sending are left alone, as is one that a PATCH is writing to.
+fn expire_upload(id: Uuid) {
+    tokio::spawn(async move {
+        let mut wait = crate::INPUT_LIFETIME;
+        loop {
+            tokio::time::sleep(wait).await;
+            let upload = {
+                let mut uploads = UPLOADS.lock().unwrap();
+                let Some(upload) = uploads.get(&id) else {
+                    // finished or terminated in the meantime
+                    return;
+                };
+                // the PATCH bumps `last_activity` when it's done, so check again after that
+                if upload.busy {
+                    wait = crate::INPUT_LIFETIME;
+                    continue;
+                }
+                let idle = upload.last_activity.elapsed();
+                if idle < crate::INPUT_LIFETIME {
+                    wait = crate::INPUT_LIFETIME - idle;
+                    continue;
+                }
+                uploads.remove(&id)
+            };
+
+            if let Some(upload) = upload {
+                info!("tus upload {} expired", id);
+                fs::remove_file(partial_path(&id, &upload.ext)).await.ok();
+            }
+            return;
+        }
+    });
+}
+
+#[delete("/tus/{id}")]
+pub async fn tus_terminate(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, TusError> {
+    check_version(&req)?;
+    let id = path.into_inner();
+
+    let upload = UPLOADS
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or(TusError::UploadNotFound)?;
+    if let Err(e) = fs::remove_file(partial_path(&id, &upload.ext)).await {
+        error!("failed to remove terminated upload {}: {}", id, e);
+    }
+    info!("tus upload {} terminated", id);
+
+    Ok(tus_response(StatusCode::NO_CONTENT).finish())
+}
+
+/// Frees the upload up for the next PATCH, even if this one was dropped mid-stream.
+struct BusyGuard(Uuid);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        if let Some(upload) = UPLOADS.lock().unwrap().get_mut(&self.0) {
+            upload.busy = false;
+            upload.last_activity = Instant::now();
+        }
+    }
+}
@@ -1,16 +1,47 @@
 use crate::{
-    converter::{format::ConverterFormat, job::Job},
+    converter::{
+        format::ConverterFormat,
+        job::{Job, JobKind},
+    },
     http::response::ApiResponse,
-    state::APP_STATE,
+    state::{self, APP_STATE},
 };
-use actix_multipart::Multipart;
+use std::{cmp::Ordering, env, io, path::Path};
+
+use actix_multipart::{Field, Multipart};
 use actix_web::{post, HttpResponse, Responder, ResponseError};
 use futures_util::StreamExt as _;
-use log::info;
+use lazy_static::lazy_static;
+use log::{info, warn};
 use tokio::{
     fs::{self, File},
     io::AsyncWriteExt,
 };
+use uuid::Uuid;
+
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024 * 1024;
+
+/// Image formats an image sequence can be made of.
+const SEQUENCE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "bmp", "tif", "tiff"];
+/// Anything past this is better off uploaded as a video.
+const MAX_SEQUENCE_IMAGES: usize = 10_000;
+
+lazy_static! {
+    /// Largest file `upload` accepts, in bytes. Read from `VERTD_MAX_UPLOAD_SIZE`.
+    pub static ref MAX_UPLOAD_SIZE: u64 = match env::var("VERTD_MAX_UPLOAD_SIZE") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                warn!(
+                    "invalid value for VERTD_MAX_UPLOAD_SIZE: '{}'. using {}.",
+                    value, DEFAULT_MAX_UPLOAD_SIZE
+                );
+                DEFAULT_MAX_UPLOAD_SIZE
+            }
+        },
+        Err(_) => DEFAULT_MAX_UPLOAD_SIZE,
+    };
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum UploadError {
@@ -24,12 +55,26 @@ pub enum UploadError {
     NoExtension,
     #[error("invalid file extension: {0}. allowed: jpg, png, gif")]
     InvalidExtension(String),
+    #[error(".{0} files can't be compressed")]
+    NotCompressible(String),
     #[error("failed to read file data")]
     GetChunk(#[from] actix_web::Error),
+    #[error("file is too large. max size: {0} bytes")]
+    TooLarge(u64),
     #[error("internal server error while writing file")]
     WriteFile(#[from] std::io::Error),
     #[error("ffprobe failed to read file: {0}")]
     ParseFile(#[from] anyhow::Error),
+    #[error("not an image: {0}. allowed: {allowed}", allowed = SEQUENCE_EXTENSIONS.join(", "))]
+    NotAnImage(String),
+    #[error("all images in a sequence have to be the same format")]
+    MixedImages,
+    #[error("no images found")]
+    NoImages,
+    #[error("too many images. max: {}", MAX_SEQUENCE_IMAGES)]
+    TooManyImages,
+    #[error("failed to read zip: {0}")]
+    ReadZip(#[from] zip::result::ZipError),
 }
 
 impl ResponseError for UploadError {
@@ -38,6 +83,7 @@ impl ResponseError for UploadError {
         let status = match self {
             UploadError::GetField(_) => actix_web::http::StatusCode::BAD_REQUEST,
             UploadError::GetChunk(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            UploadError::TooLarge(_) => actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
             UploadError::WriteFile(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
             _ => actix_web::http::StatusCode::BAD_REQUEST,
         };
@@ -47,7 +93,228 @@ impl ResponseError for UploadError {
 }
 
 #[post("/upload")]
-pub async fn upload(mut payload: Multipart) -> Result<impl Responder, UploadError> {
+pub async fn upload(payload: Multipart) -> Result<impl Responder, UploadError> {
+    let job = receive_upload(payload, JobKind::Conversion).await?;
+    Ok(ApiResponse::Success(job))
+}
+
+/// Same as `upload`, but the job can only be started as a compression.
+#[post("/upload/compress")]
+pub async fn upload_compression(payload: Multipart) -> Result<impl Responder, UploadError> {
+    let job = receive_upload(payload, JobKind::Compression).await?;
+    Ok(ApiResponse::Success(job))
+}
+
+/// Takes the images of an image sequence, either as several `file` fields or as a single
+/// zip. They play back in the order of their names, counting "2" before "10".
+#[post("/upload/sequence")]
+pub async fn upload_sequence(mut payload: Multipart) -> Result<impl Responder, UploadError> {
+    let id = Uuid::new_v4();
+    let dir = format!("input/{}", id);
+    fs::create_dir_all(&dir).await?;
+    let mut partial = PartialUpload {
+        path: dir.clone(),
+        done: false,
+    };
+
+    // (name as uploaded, where it was written to)
+    let mut images: Vec<(String, String)> = Vec::new();
+    let mut first_filename = None;
+    // the size limit goes for the whole sequence, not each image in it
+    let mut received = 0u64;
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+
+        let Some(content_disposition) = field.content_disposition() else {
+            continue;
+        };
+        if content_disposition.get_name() != Some("file") {
+            continue;
+        }
+        let filename = content_disposition
+            .get_filename()
+            .ok_or_else(|| UploadError::NoFilename)?
+            .to_string();
+        first_filename.get_or_insert_with(|| filename.clone());
+
+        let ext = sequence_extension(&filename);
+        if ext.as_deref() == Some("zip") {
+            let zip_path = format!("{}/upload-{}.zip", dir, images.len());
+            // the zip itself only counts until it's been unpacked
+            save_field(&mut field, zip_path.clone(), *MAX_UPLOAD_SIZE - received).await?;
+            let (zip, dir, start) = (zip_path.clone(), dir.clone(), images.len());
+            let limit = *MAX_UPLOAD_SIZE - received;
+            let (extracted, written) =
+                tokio::task::spawn_blocking(move || extract_images(&zip, &dir, start, limit))
+                    .await
+                    .map_err(|e| anyhow::anyhow!(e))??;
+            fs::remove_file(&zip_path).await?;
+            images.extend(extracted);
+            received += written;
+        } else if ext.is_some() {
+            let path = format!("{}/upload-{}", dir, images.len());
+            received += save_field(&mut field, path.clone(), *MAX_UPLOAD_SIZE - received).await?;
+            images.push((filename, path));
+        } else {
+            return Err(UploadError::NotAnImage(filename));
+        }
+
+        if images.len() > MAX_SEQUENCE_IMAGES {
+            return Err(UploadError::TooManyImages);
+        }
+    }
+
+    let filename = first_filename.ok_or(UploadError::NoFile)?;
+    if images.is_empty() {
+        return Err(UploadError::NoImages);
+    }
+    let mut extensions = images
+        .iter()
+        .filter_map(|(name, _)| sequence_extension(name));
+    let ext = extensions.next().ok_or(UploadError::NoImages)?;
+    if extensions.any(|other| other != ext) {
+        return Err(UploadError::MixedImages);
+    }
+
+    // number them the way ffmpeg's image2 demuxer expects
+    images.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+    for (i, (_, path)) in images.iter().enumerate() {
+        fs::rename(path, format!("{}/{:06}.{}", dir, i + 1, ext)).await?;
+    }
+    partial.done = true;
+    info!("uploaded image sequence of {} {} images", images.len(), ext);
+
+    let mut job = Job::with_id(id, job_token(), ext);
+    job.filename = Some(filename);
+    job.set_image_sequence(images.len() as u64);
+    let job = register_job(job).await?;
+    Ok(ApiResponse::Success(job))
+}
+
+/// The image format of a file in a sequence, with the spellings ffmpeg doesn't care about
+/// folded together. "zip" comes back as-is, anything else as `None`.
+fn sequence_extension(filename: &str) -> Option<String> {
+    let ext = filename.rsplit_once('.')?.1.to_lowercase();
+    match ext.as_str() {
+        "jpeg" => Some("jpg".to_string()),
+        "tiff" => Some("tif".to_string()),
+        "zip" => Some(ext),
+        _ if SEQUENCE_EXTENSIONS.contains(&ext.as_str()) => Some(ext),
+        _ => None,
+    }
+}
+
+/// What can go wrong pulling images out of a zip. `UploadError` can't be sent back from
+/// the blocking thread, so this gets turned into one afterwards.
+enum ExtractError {
+    Zip(zip::result::ZipError),
+    TooManyImages,
+    TooLarge,
+}
+
+impl From<zip::result::ZipError> for ExtractError {
+    fn from(e: zip::result::ZipError) -> Self {
+        ExtractError::Zip(e)
+    }
+}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> Self {
+        ExtractError::Zip(e.into())
+    }
+}
+
+impl From<ExtractError> for UploadError {
+    fn from(e: ExtractError) -> Self {
+        match e {
+            ExtractError::Zip(zip::result::ZipError::Io(e)) => UploadError::WriteFile(e),
+            ExtractError::Zip(e) => UploadError::ReadZip(e),
+            ExtractError::TooManyImages => UploadError::TooManyImages,
+            ExtractError::TooLarge => UploadError::TooLarge(*MAX_UPLOAD_SIZE),
+        }
+    }
+}
+
+/// Pulls the images out of a zip into `dir`, numbering the files from `start` so they
+/// don't clash with the rest of the upload. Anything that isn't an image gets skipped.
+/// Gives up once more than `limit` bytes come out, otherwise returns how many did.
+fn extract_images(
+    zip_path: &str,
+    dir: &str,
+    start: usize,
+    limit: u64,
+) -> Result<(Vec<(String, String)>, u64), ExtractError> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
+    let mut images = Vec::new();
+    let mut extracted = 0u64;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name()?.to_string();
+        let basename = name.rsplit('/').next().unwrap_or_default();
+        // macOS likes to leave its resource forks in there
+        if entry.is_dir() || name.starts_with("__MACOSX/") || basename.starts_with('.') {
+            continue;
+        }
+        if sequence_extension(basename).is_none_or(|ext| ext == "zip") {
+            continue;
+        }
+        if start + images.len() >= MAX_SEQUENCE_IMAGES {
+            return Err(ExtractError::TooManyImages);
+        }
+
+        let path = format!("{}/upload-{}", dir, start + images.len());
+        let mut file = std::fs::File::create(Path::new(&path))?;
+        // the sizes in the zip can't be trusted, so count what actually comes out
+        let remaining = limit - extracted;
+        let written = io::copy(&mut io::Read::take(&mut entry, remaining + 1), &mut file)?;
+        extracted += written;
+        if written > remaining {
+            return Err(ExtractError::TooLarge);
+        }
+        images.push((name, path));
+    }
+
+    Ok((images, extracted))
+}
+
+/// Compares names the way people count, so "frame2" comes before "frame10".
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                // leading zeros are gone, so the longer number is the bigger one
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Takes a run of digits off the front, without its leading zeros.
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        number.push(digit);
+    }
+    number.trim_start_matches('0').to_string()
+}
+
+async fn receive_upload(mut payload: Multipart, kind: JobKind) -> Result<Job, UploadError> {
     let mut job: Option<Job> = None;
     while let Some(item) = payload.next().await {
         let mut field = item?;
@@ -66,58 +333,127 @@ pub async fn upload(mut payload: Multipart) -> Result<impl Responder, UploadErro
             .get_filename()
             .ok_or_else(|| UploadError::NoFilename)?;
 
-        let ext = filename
-            .split('.')
-            .last()
-            .and_then(|ext| {
-                Some(
-                    ext.chars()
-                        .filter(|c| c.is_alphanumeric())
-                        .collect::<String>(),
-                )
-            })
-            .ok_or_else(|| UploadError::NoExtension)?;
-
-        if let Err(e) = ext.parse::<ConverterFormat>() {
+        let ext = input_extension(filename)?;
+        check_kind(&ext, kind)?;
+
+        info!("uploaded file: {}", filename);
+
+        let mut our_job = Job::new(job_token(), ext.to_string());
+        our_job.filename = Some(filename.to_string());
+        our_job.kind = kind;
+        save_field(
+            &mut field,
+            format!("input/{}.{}", our_job.id, ext),
+            *MAX_UPLOAD_SIZE,
+        )
+        .await?;
+        job = Some(our_job);
+        break;
+    }
+    let job = job.ok_or_else(|| UploadError::NoFile)?;
+    register_job(job).await
+}
+
+/// The extension of an uploaded file, as long as it's something we can convert.
+pub fn input_extension(filename: &str) -> Result<String, UploadError> {
+    let ext = filename
+        .split('.')
+        .next_back()
+        .map(|ext| {
+            ext.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+        })
+        .ok_or_else(|| UploadError::NoExtension)?;
+
+    match ext.parse::<ConverterFormat>() {
+        Ok(format) if format.is_output_only() => return Err(UploadError::InvalidExtension(ext)),
+        Ok(_) => {}
+        Err(e) => {
             log::error!("failed to parse file extension: {}", e);
             return Err(UploadError::InvalidExtension(ext));
         }
+    }
 
-        info!("uploaded file: {}", filename);
+    Ok(ext)
+}
+
+/// Turns away uploads that could never be started as `kind`, before they're written out.
+pub fn check_kind(ext: &str, kind: JobKind) -> Result<(), UploadError> {
+    let compressible = ext
+        .parse::<ConverterFormat>()
+        .is_ok_and(|format| format.can_target_size());
+    if kind == JobKind::Compression && !compressible {
+        return Err(UploadError::NotCompressible(ext.to_string()));
+    }
+    Ok(())
+}
+
+pub fn job_token() -> String {
+    let rand: [u8; 64] = rand::random();
+    hex::encode(rand)
+}
+
+/// Takes on a job whose input has been fully written to `input/`.
+pub async fn register_job(mut job: Job) -> Result<Job, UploadError> {
+    let mut app_state = APP_STATE.lock().await;
+    app_state.insert_job(job.clone());
+    drop(app_state);
+    // remove the job after an hour
+    state::expire_input(job.id, job.input_location(), crate::INPUT_LIFETIME);
 
-        let mut bytes = Vec::new();
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            bytes.extend_from_slice(&data);
+    job.probe_length().await?;
+    // keep the frame count (or duration) so it doesn't have to be probed again for progress.
+    // the job is up for grabs while probing, so it may well have been started by now
+    let mut app_state = APP_STATE.lock().await;
+    app_state.update_job(&job.id, |stored| stored.copy_length(&job));
+    drop(app_state);
+    Ok(job)
+}
+
+/// Streams the field into `path` chunk by chunk, giving up once it goes past `limit`
+/// bytes. Whatever was written is removed if the upload doesn't finish. Returns how
+/// many bytes were written.
+async fn save_field(field: &mut Field, path: String, limit: u64) -> Result<u64, UploadError> {
+    let mut partial = PartialUpload { path, done: false };
+    let mut file = File::create(&partial.path).await?;
+    let mut written = 0u64;
+
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        written += data.len() as u64;
+        if written > limit {
+            return Err(UploadError::TooLarge(*MAX_UPLOAD_SIZE));
+        }
+        file.write_all(&data).await?;
+    }
+
+    file.flush().await?;
+    partial.done = true;
+    Ok(written)
+}
+
+/// Removes a half-written upload, including when the request gets dropped mid-stream.
+/// Image sequences are a whole directory of them.
+struct PartialUpload {
+    path: String,
+    done: bool,
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if !self.done {
+            info!("upload aborted, removing {}", self.path);
+            let removed = if Path::new(&self.path).is_dir() {
+                std::fs::remove_dir_all(&self.path)
+            } else {
+                std::fs::remove_file(&self.path)
+            };
+            if let Err(e) = removed {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::error!("failed to remove partial upload: {}", e);
+                }
+            }
         }
-        let rand: [u8; 64] = rand::random();
-        let token = hex::encode(rand);
-        let our_job = Job::new(token, ext.to_string());
-        job = Some(our_job.clone());
-        let mut app_state = APP_STATE.lock().await;
-        // fs::write(format!("input/{}.{}", our_job.id, ext), &bytes).await?;
-        let mut file = File::create(format!("input/{}.{}", our_job.id, ext)).await?;
-        file.write_all(&bytes).await?;
-        file.flush().await?;
-        drop(file);
-        app_state.jobs.insert(our_job.id, our_job.clone());
-        // spawn a new task which waits an hour before removing the job
-        tokio::spawn(async move {
-            tokio::time::sleep(crate::INPUT_LIFETIME).await;
-            info!(
-                "{:?} elapsed, removing {}",
-                crate::INPUT_LIFETIME,
-                our_job.id
-            );
-            let mut app_state = APP_STATE.lock().await;
-            app_state.jobs.remove(&our_job.id);
-            fs::remove_file(format!("input/{}.{}", our_job.id, ext))
-                .await
-                .ok();
-        });
-        break;
     }
-    let mut job = job.ok_or_else(|| UploadError::NoFile)?;
-    job.total_frames().await?;
-    Ok(ApiResponse::Success(job))
 }
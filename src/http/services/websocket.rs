@@ -1,22 +1,27 @@
-use std::collections::BTreeMap;
-use std::env;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 
 use actix_web::{get, rt, web, Error, HttpRequest, HttpResponse};
 use actix_ws::AggregatedMessage;
-use discord_webhook2::{message, webhook::DiscordWebhook};
 use futures_util::StreamExt as _;
-use log::{error, warn};
+use log::info;
 use serde::{Deserialize, Serialize};
-use tokio::fs;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{
-    converter::{format::ConverterFormat, job::ProgressUpdate, speed::ConversionSpeed, Converter},
-    state::APP_STATE,
-    OUTPUT_LIFETIME,
+use crate::converter::{
+    codec::ConversionOptions,
+    frames::FrameExportOptions,
+    job::ProgressUpdate,
+    runner::{self, StartError, StartedJob},
+    speed::ConversionSpeed,
+    storyboard::StoryboardOptions,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data", rename_all = "camelCase")]
 pub enum Message {
     #[serde(rename = "startJob", rename_all = "camelCase")]
@@ -25,11 +30,62 @@ pub enum Message {
         job_id: Uuid,
         to: String,
         speed: ConversionSpeed,
+        #[serde(flatten)]
+        options: ConversionOptions,
+    },
+
+    #[serde(rename = "startCompression", rename_all = "camelCase")]
+    StartCompression {
+        token: String,
+        job_id: Uuid,
+        /// How much smaller the output should be, in percent.
+        reduction: u8,
+        speed: ConversionSpeed,
+    },
+
+    #[serde(rename = "startSequence", rename_all = "camelCase")]
+    StartSequence {
+        token: String,
+        job_id: Uuid,
+        to: String,
+        /// How many of the images make up a second of video.
+        fps: u32,
+        speed: ConversionSpeed,
+        #[serde(flatten)]
+        options: ConversionOptions,
     },
 
+    #[serde(rename = "startStoryboard", rename_all = "camelCase")]
+    StartStoryboard {
+        token: String,
+        job_id: Uuid,
+        #[serde(flatten)]
+        options: StoryboardOptions,
+    },
+
+    #[serde(rename = "startFrameExport", rename_all = "camelCase")]
+    StartFrameExport {
+        token: String,
+        job_id: Uuid,
+        #[serde(flatten)]
+        options: FrameExportOptions,
+    },
+
+    #[serde(rename = "cancelJob", rename_all = "camelCase")]
+    CancelJob { token: String, job_id: Uuid },
+
+    #[serde(rename = "queuePosition", rename_all = "camelCase")]
+    QueuePosition { job_id: Uuid, position: usize },
+
+    #[serde(rename = "jobStarted", rename_all = "camelCase")]
+    JobStarted { job_id: Uuid },
+
     #[serde(rename = "jobFinished", rename_all = "camelCase")]
     JobFinished { job_id: Uuid },
 
+    #[serde(rename = "jobCancelled", rename_all = "camelCase")]
+    JobCancelled { job_id: Uuid },
+
     #[serde(rename = "progressUpdate", rename_all = "camelCase")]
     ProgressUpdate(ProgressUpdate),
 
@@ -37,9 +93,9 @@ pub enum Message {
     Error { message: String },
 }
 
-impl Into<String> for Message {
-    fn into(self) -> String {
-        serde_json::to_string(&self).unwrap()
+impl From<Message> for String {
+    fn from(message: Message) -> Self {
+        serde_json::to_string(&message).unwrap()
     }
 }
 
@@ -52,7 +108,21 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
         .max_continuation_size(2_usize.pow(20));
 
     rt::spawn(async move {
-        while let Some(Ok(AggregatedMessage::Text(text))) = stream.next().await {
+        let mut started = SocketJobs::default();
+
+        while let Some(Ok(message)) = stream.next().await {
+            let text = match message {
+                AggregatedMessage::Text(text) => text,
+                AggregatedMessage::Ping(bytes) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                AggregatedMessage::Close(_) => break,
+                _ => continue,
+            };
+
             let message: Message = match serde_json::from_str(&text) {
                 Ok(message) => message,
                 Err(e) => {
@@ -60,163 +130,95 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
                         message: format!("failed to parse message: {}", e),
                     }
                     .into();
-                    session.text(message).await.unwrap();
+                    if session.text(message).await.is_err() {
+                        break;
+                    }
                     continue;
                 }
             };
 
-            match message {
+            let (job_id, result) = match message {
                 Message::StartJob {
                     token,
                     job_id,
                     to,
                     speed,
-                } => {
-                    let Some(mut job) = ({
-                        let mut app_state = APP_STATE.lock().await;
-                        let job = app_state.jobs.get_mut(&job_id);
-                        let clone = job.as_ref().map(|j| (*j).clone());
-                        if let Some(job) = job {
-                            if job.completed {
-                                let message: String = Message::Error {
-                                    message: "job already completed".to_string(),
-                                }
-                                .into();
-                                session.text(message).await.unwrap();
-                                continue;
-                            }
-                            job.to = Some(to.clone());
-                        }
-                        clone
-                    }) else {
-                        let message: String = Message::Error {
-                            message: "job not found".to_string(),
-                        }
-                        .into();
-                        session.text(message).await.unwrap();
-                        continue;
-                    };
+                    options,
+                } => (
+                    job_id,
+                    runner::start_job(job_id, &token, &to, speed, options).await,
+                ),
 
-                    if job.auth != token {
-                        let message: String = Message::Error {
-                            message: "invalid token".to_string(),
-                        }
-                        .into();
-                        session.text(message).await.unwrap();
-                        continue;
-                    }
+                Message::StartCompression {
+                    token,
+                    job_id,
+                    reduction,
+                    speed,
+                } => (
+                    job_id,
+                    runner::start_compression(job_id, &token, reduction, speed).await,
+                ),
 
-                    let Ok(from) = job.from.parse::<ConverterFormat>() else {
-                        let message: String = Message::Error {
-                            message: "invalid input format".to_string(),
-                        }
-                        .into();
-                        session.text(message).await.unwrap();
-                        continue;
-                    };
+                Message::StartSequence {
+                    token,
+                    job_id,
+                    to,
+                    fps,
+                    speed,
+                    options,
+                } => (
+                    job_id,
+                    runner::start_sequence(job_id, &token, &to, fps, speed, options).await,
+                ),
 
-                    let Ok(to) = to.parse::<ConverterFormat>() else {
+                Message::StartStoryboard {
+                    token,
+                    job_id,
+                    options,
+                } => (
+                    job_id,
+                    runner::start_storyboard(job_id, &token, options).await,
+                ),
+
+                Message::StartFrameExport {
+                    token,
+                    job_id,
+                    options,
+                } => (
+                    job_id,
+                    runner::start_frame_export(job_id, &token, options).await,
+                ),
+
+                Message::CancelJob { token, job_id } => {
+                    if let Err(e) = runner::cancel_job(job_id, &token).await {
                         let message: String = Message::Error {
-                            message: "invalid output format".to_string(),
+                            message: e.to_string(),
                         }
                         .into();
-                        session.text(message).await.unwrap();
-                        continue;
-                    };
-
-                    let converter = Converter::new(from, to, speed);
-
-                    let mut rx = match converter.convert(&mut job).await {
-                        Ok(rx) => rx,
-                        Err(e) => {
-                            let message: String = Message::Error {
-                                message: format!("failed to convert: {}", e),
-                            }
-                            .into();
-                            session.text(message).await.unwrap();
-                            continue;
-                        }
-                    };
-
-                    let mut logs = Vec::new();
-
-                    while let Some(update) = rx.recv().await {
-                        match update {
-                            ProgressUpdate::Error(err) => {
-                                logs.push(err);
-                            }
-                            _ => {
-                                let message: String = Message::ProgressUpdate(update).into();
-                                session.text(message).await.unwrap();
-                            }
+                        if session.text(message).await.is_err() {
+                            break;
                         }
                     }
+                    continue;
+                }
 
-                    let mut app_state = APP_STATE.lock().await;
-                    if let Some(job) = app_state.jobs.get_mut(&job_id) {
-                        job.completed = true;
+                _ => {
+                    let message: String = Message::Error {
+                        message: "unexpected message type".to_string(),
                     }
-                    drop(app_state);
-
-                    // check if output/{}.{} exists and isn't empty
-                    let is_empty = fs::metadata(&format!("output/{}.{}", job_id, to.to_string()))
-                        .await
-                        .map(|m| m.len() == 0)
-                        .unwrap_or(true);
-
-                    if is_empty {
-                        log::error!("job {} failed", job_id);
-                        let message: String = Message::Error {
-                            message: "oops -- your job failed! maddie has been notified :)"
-                                .to_string(),
-                        }
-                        .into();
-                        session.text(message).await.unwrap();
-
-                        let from = job.from.clone();
-                        let to = to.to_string().to_string();
-
-                        tokio::spawn(async move {
-                            if let Err(e) =
-                                handle_job_failure(job_id, from, to, logs.join("\n")).await
-                            {
-                                log::error!("failed to handle job failure: {}", e);
-                            }
-                        });
-                    } else {
-                        let message: String = Message::JobFinished { job_id }.into();
-                        session.text(message).await.unwrap();
+                    .into();
+                    if session.text(message).await.is_err() {
+                        break;
                     }
-
-                    tokio::spawn(async move {
-                        tokio::time::sleep(OUTPUT_LIFETIME).await;
-                        let mut app_state = APP_STATE.lock().await;
-                        app_state.jobs.remove(&job_id);
-                        drop(app_state);
-
-                        let path = format!("output/{}.{}", job_id, to.to_string());
-                        if let Err(e) = fs::remove_file(&path).await {
-                            if e.kind() != std::io::ErrorKind::NotFound {
-                                log::error!("failed to remove output file: {}", e);
-                            }
-                        }
-                    });
-
-                    match fs::remove_file(&format!("input/{}.{}", job.id, job.from)).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("failed to remove input file: {}", e);
-                            let message: String = Message::Error {
-                                message: format!("failed to remove input file: {}", e),
-                            }
-                            .into();
-                            session.text(message).await.unwrap();
-                            continue;
-                        }
-                    };
+                    continue;
                 }
+            };
 
-                _ => {}
+            if track_job(&mut session, &mut started, job_id, result)
+                .await
+                .is_err()
+            {
+                break;
             }
         }
     });
@@ -224,38 +226,86 @@ pub async fn websocket(req: HttpRequest, stream: web::Payload) -> Result<HttpRes
     Ok(res)
 }
 
-async fn handle_job_failure(
+/// Jobs started over a socket that are still queued or running, each dropped once its
+/// event feed closes. Shared with the tasks forwarding those feeds.
+type LiveJobs = Arc<Mutex<HashMap<Uuid, Arc<CancellationToken>>>>;
+
+/// The jobs a socket has going. They get cancelled once it goes away, however its task ends.
+#[derive(Default)]
+struct SocketJobs(LiveJobs);
+
+impl Drop for SocketJobs {
+    fn drop(&mut self) {
+        // the client is gone, nobody is going to download these
+        for (job_id, cancel) in self.0.lock().unwrap().drain() {
+            if !cancel.is_cancelled() {
+                info!("websocket closed, cancelling job {}", job_id);
+                cancel.cancel();
+            }
+        }
+    }
+}
+
+/// Relays a freshly started job's events to the client, or tells it why the job didn't start.
+async fn track_job(
+    session: &mut actix_ws::Session,
+    started: &mut SocketJobs,
     job_id: Uuid,
-    from: String,
-    to: String,
-    logs: String,
-) -> anyhow::Result<()> {
-    // Check for the webhook URL. If it's not set or empty, just warn and exit gracefully.
-    let client_url = match env::var("WEBHOOK_URL") {
-        Ok(url) if !url.is_empty() => url,
-        _ => {
-            warn!("WEBHOOK_URL not set. Skipping failure notification.");
-            return Ok(());
+    result: Result<StartedJob, StartError>,
+) -> Result<(), actix_ws::Closed> {
+    match result {
+        Ok(job) => {
+            let cancel = Arc::new(job.cancel);
+            started.0.lock().unwrap().insert(job_id, cancel.clone());
+            rt::spawn(forward_events(
+                session.clone(),
+                job.events,
+                started.0.clone(),
+                job_id,
+                cancel,
+            ));
+            Ok(())
         }
-    };
-    
-    let mentions = env::var("WEBHOOK_PINGS").unwrap_or_else(|_| "".to_string());
-
-    let mut files = BTreeMap::new();
-    files.insert(format!("{}.log", job_id), logs.as_bytes().to_vec());
-
-    let client = DiscordWebhook::new(&client_url)?;
-    let message = message::Message::new(|m| {
-        m.content(format!("🚨🚨🚨 {}", mentions)).embed(|e| {
-            e.title("vertd job failed!")
-                .field(|f| f.name("job id").value(job_id))
-                .field(|f| f.name("from").value(format!(".{}", from)).inline(true))
-                .field(|f| f.name("to").value(format!(".{}", to)).inline(true))
-                .color(0xff83fa)
-        })
-    });
+        Err(e) => {
+            let message: String = Message::Error {
+                message: e.to_string(),
+            }
+            .into();
+            session.text(message).await
+        }
+    }
+}
 
-    client.send_with_files(&message, files).await?;
+/// Relays everything a job reports back to the client until the job is done, then stops
+/// the socket from cancelling it.
+async fn forward_events(
+    mut session: actix_ws::Session,
+    mut events: broadcast::Receiver<Message>,
+    jobs: LiveJobs,
+    job_id: Uuid,
+    cancel: Arc<CancellationToken>,
+) {
+    loop {
+        let message = match events.recv().await {
+            Ok(message) => message,
+            // fell behind -- the next update supersedes the ones we missed anyway
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => {
+                let mut jobs = jobs.lock().unwrap();
+                // unless the job has been started again in the meantime
+                if jobs
+                    .get(&job_id)
+                    .is_some_and(|current| Arc::ptr_eq(current, &cancel))
+                {
+                    jobs.remove(&job_id);
+                }
+                return;
+            }
+        };
 
-    Ok(())
-}
\ No newline at end of file
+        let message: String = message.into();
+        if session.text(message).await.is_err() {
+            break;
+        }
+    }
+}
@@ -9,7 +9,6 @@ use dotenv::dotenv;
 use env_logger::Env;
 use http::start_http;
 use log::{error, info};
-use tokio::fs;
 
 pub const INPUT_LIFETIME: Duration = Duration::from_secs(60 * 60);
 pub const OUTPUT_LIFETIME: Duration = Duration::from_secs(60 * 60);
@@ -72,6 +71,11 @@ async fn main() -> anyhow::Result<()> {
         ffmpeg_version, ffprobe_version
     );
 
+    info!(
+        "running up to {} conversion(s) at once",
+        converter::queue::QUEUE.max_concurrent()
+    );
+
     let gpu = get_gpu().await;
 
     match gpu {
@@ -89,14 +93,12 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
-    // remove input/ and output/ recursively if they exist -- we don't care if this fails tho
-    let _ = fs::remove_dir_all("input").await;
-    let _ = fs::remove_dir_all("output").await;
-
-    // create input/ and output/ directories
-    fs::create_dir("input").await?;
-    fs::create_dir("output").await?;
+    // open the store up front, so a bad path fails here rather than on the first request
+    let store = state::store::from_env()?;
+    // pick up whatever jobs survived the last run instead of wiping input/ and output/
+    state::restore_jobs(store).await?;
 
     start_http(auth_token).await?;
+    state::flush_store().await;
     Ok(())
 }
\ No newline at end of file
@@ -1,19 +1,108 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use lazy_static::lazy_static;
-use tokio::sync::Mutex;
+use log::{error, info, warn};
+use store::{JobStore, MemoryStore};
+use tokio::{
+    fs,
+    sync::{broadcast, Mutex},
+};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::converter::job::Job;
+use crate::{
+    converter::{
+        job::{Job, JobProgress, JobStatus},
+        storyboard,
+    },
+    http::services::websocket::Message,
+    INPUT_LIFETIME, OUTPUT_LIFETIME,
+};
+
+pub mod store;
 
 pub struct AppState {
-    pub jobs: HashMap<Uuid, Job>,
+    jobs: HashMap<Uuid, Job>,
+    store: Box<dyn JobStore>,
+    /// Cancels the conversion of a job that is currently queued or running.
+    pub cancellations: HashMap<Uuid, CancellationToken>,
+    /// Progress of the latest conversion of each job. Not persisted.
+    pub progress: HashMap<Uuid, JobProgress>,
+    /// Live feed of each conversion in flight, shared by everyone watching it.
+    events: HashMap<Uuid, broadcast::Sender<Message>>,
 }
 
+// plenty for a burst of progress lines -- slow subscribers just skip ahead
+const EVENTS_CAPACITY: usize = 64;
+
 impl AppState {
+    /// Nothing gets persisted until `restore_jobs` swaps in the configured store.
     pub fn default() -> Self {
         Self {
             jobs: HashMap::new(),
+            store: Box::new(MemoryStore),
+            cancellations: HashMap::new(),
+            progress: HashMap::new(),
+            events: HashMap::new(),
+        }
+    }
+
+    pub fn get_job(&self, id: &Uuid) -> Option<&Job> {
+        self.jobs.get(id)
+    }
+
+    pub fn insert_job(&mut self, job: Job) {
+        if let Err(e) = self.store.save(&job) {
+            error!("failed to persist job {}: {}", job.id, e);
+        }
+        self.jobs.insert(job.id, job);
+    }
+
+    /// Applies `f` to the job and persists the result, returning a copy of the updated job.
+    pub fn update_job(&mut self, id: &Uuid, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let job = self.jobs.get_mut(id)?;
+        f(job);
+        if let Err(e) = self.store.save(job) {
+            error!("failed to persist job {}: {}", id, e);
+        }
+        Some(job.clone())
+    }
+
+    pub fn remove_job(&mut self, id: &Uuid) -> Option<Job> {
+        if let Err(e) = self.store.delete(id) {
+            error!("failed to remove job {} from the store: {}", id, e);
+        }
+        self.progress.remove(id);
+        self.events.remove(id);
+        self.jobs.remove(id)
+    }
+
+    /// The job's event feed, created if nobody has asked for it yet.
+    pub fn job_events(&mut self, id: Uuid) -> broadcast::Sender<Message> {
+        self.events
+            .entry(id)
+            .or_insert_with(|| broadcast::channel(EVENTS_CAPACITY).0)
+            .clone()
+    }
+
+    /// Starts listening to the job's event feed, if a conversion is in flight.
+    pub fn subscribe_events(&self, id: &Uuid) -> Option<broadcast::Receiver<Message>> {
+        self.events.get(id).map(broadcast::Sender::subscribe)
+    }
+
+    /// Drops the job's event feed so its subscribers see the end of the stream.
+    /// Does nothing if the feed has been replaced by a newer conversion in the meantime.
+    pub fn close_events(&mut self, id: &Uuid, events: &broadcast::Sender<Message>) {
+        if self
+            .events
+            .get(id)
+            .is_some_and(|current| current.same_channel(events))
+        {
+            self.events.remove(id);
         }
     }
 }
@@ -21,3 +110,168 @@ impl AppState {
 lazy_static! {
     pub static ref APP_STATE: Arc<Mutex<AppState>> = Arc::new(Mutex::new(AppState::default()));
 }
+
+/// Writes out whatever the store hasn't yet, e.g. before shutting down.
+pub async fn flush_store() {
+    let app_state = APP_STATE.lock().await;
+    if let Err(e) = app_state.store.flush() {
+        error!("failed to flush the job store: {}", e);
+    }
+}
+
+/// Removes an upload, be it a single file or an image sequence's directory.
+pub async fn remove_input(path: &str) -> std::io::Result<()> {
+    if fs::metadata(path).await?.is_dir() {
+        fs::remove_dir_all(path).await
+    } else {
+        fs::remove_file(path).await
+    }
+}
+
+/// Removes the job and its input (see `Job::input_location`) once `after` has elapsed,
+/// unless it's been started since. Queued and running jobs get another `INPUT_LIFETIME`,
+/// and finished ones are left to `expire_output`.
+pub fn expire_input(id: Uuid, path: String, mut after: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(after).await;
+            let mut app_state = APP_STATE.lock().await;
+            match app_state.get_job(&id).map(|job| job.status) {
+                Some(status) if status.is_active() => {
+                    after = INPUT_LIFETIME;
+                    continue;
+                }
+                Some(status) if status.is_finished() => return,
+                _ => {}
+            }
+
+            info!("input lifetime elapsed, removing {}", id);
+            app_state.remove_job(&id);
+            drop(app_state);
+            remove_input(&path).await.ok();
+            return;
+        }
+    });
+}
+
+/// Removes the job's output, along with the sprite map that storyboards come with.
+pub async fn remove_output(id: Uuid, ext: &str) {
+    for path in [
+        format!("output/{}.{}", id, ext),
+        storyboard::sprite_map_path(id),
+    ] {
+        if let Err(e) = fs::remove_file(&path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove output file: {}", e);
+            }
+        }
+    }
+}
+
+/// Removes the job and its output files once `after` has elapsed.
+pub fn expire_output(id: Uuid, ext: String, after: Duration) {
+    tokio::spawn(async move {
+        tokio::time::sleep(after).await;
+        let mut app_state = APP_STATE.lock().await;
+        app_state.remove_job(&id);
+        drop(app_state);
+
+        remove_output(id, &ext).await;
+    });
+}
+
+/// How much of `lifetime` is left for the file at `path`, based on when it was last written.
+async fn remaining_lifetime(path: &str, lifetime: Duration) -> Option<Duration> {
+    let modified = fs::metadata(path).await.ok()?.modified().ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    Some(lifetime.saturating_sub(age))
+}
+
+/// Takes over `store` and re-adopts the jobs left over from a previous run in it. Jobs
+/// whose files are gone are dropped, conversions that were interrupted go back to being
+/// plain uploads, and any file in input/ or output/ that no job owns gets deleted.
+pub async fn restore_jobs(store: Box<dyn JobStore>) -> anyhow::Result<()> {
+    fs::create_dir_all("input").await?;
+    fs::create_dir_all("output").await?;
+
+    let mut app_state = APP_STATE.lock().await;
+    app_state.store = store;
+    let jobs = app_state.store.load_all()?;
+    let mut owned_files = HashSet::new();
+
+    for mut job in jobs {
+        let input_path = job.input_location();
+        let output_path = job
+            .to
+            .as_ref()
+            .map(|to| format!("output/{}.{}", job.id, to));
+
+        if job.status.is_finished() {
+            let remaining = match &output_path {
+                Some(path) => remaining_lifetime(path, OUTPUT_LIFETIME).await,
+                None => None,
+            };
+            match (remaining, output_path) {
+                (Some(remaining), Some(path)) => {
+                    expire_output(job.id, job.to.clone().unwrap_or_default(), remaining);
+                    owned_files.insert(path);
+                    owned_files.insert(storyboard::sprite_map_path(job.id));
+                    app_state.jobs.insert(job.id, job);
+                }
+                _ => {
+                    app_state.remove_job(&job.id);
+                }
+            }
+            continue;
+        }
+
+        // whatever ffmpeg left behind is a partial file
+        if let Some(path) = &output_path {
+            fs::remove_file(path).await.ok();
+        }
+
+        match remaining_lifetime(&input_path, INPUT_LIFETIME).await {
+            Some(remaining) => {
+                if job.status.is_active() {
+                    info!(
+                        "job {} was interrupted mid-conversion, it can be started again",
+                        job.id
+                    );
+                    job.to = None;
+                    job.status = JobStatus::Uploaded;
+                }
+                expire_input(job.id, input_path.clone(), remaining);
+                owned_files.insert(input_path);
+                app_state.insert_job(job);
+            }
+            None => {
+                app_state.remove_job(&job.id);
+            }
+        }
+    }
+
+    info!("re-adopted {} job(s) from the store", app_state.jobs.len());
+    drop(app_state);
+
+    for dir in ["input", "output"] {
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            if owned_files.contains(&path) {
+                continue;
+            }
+            let removed = if entry.file_type().await?.is_dir() {
+                fs::remove_dir_all(&path).await
+            } else {
+                fs::remove_file(&path).await
+            };
+            if let Err(e) = removed {
+                warn!("failed to remove orphaned file {}: {}", path, e);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::env;
+
+use anyhow::Context;
+use log::info;
+use uuid::Uuid;
+
+use crate::converter::job::Job;
+
+/// Backend that job records get persisted to, so they survive a restart.
+pub trait JobStore: Send {
+    fn load_all(&self) -> anyhow::Result<Vec<Job>>;
+    fn save(&self, job: &Job) -> anyhow::Result<()>;
+    fn delete(&self, id: &Uuid) -> anyhow::Result<()>;
+    /// Makes sure everything saved so far is on disk.
+    fn flush(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Doesn't persist anything -- jobs only live as long as the process does.
+pub struct MemoryStore;
+
+impl JobStore for MemoryStore {
+    fn load_all(&self) -> anyhow::Result<Vec<Job>> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _job: &Job) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _id: &Uuid) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SledStore {
+    db: sled::Db,
+}
+
+impl SledStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let db =
+            sled::open(path).with_context(|| format!("failed to open job store at {}", path))?;
+        Ok(Self { db })
+    }
+}
+
+impl JobStore for SledStore {
+    fn load_all(&self) -> anyhow::Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            match serde_json::from_slice::<Job>(&value) {
+                Ok(job) => jobs.push(job),
+                Err(e) => {
+                    // probably written by an older vertd -- not worth failing the boot over
+                    log::warn!(
+                        "dropping unreadable job record {}: {}",
+                        hex::encode(&key),
+                        e
+                    );
+                    self.db.remove(key)?;
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    // sled flushes in the background every half a second, which is plenty for job records.
+    // syncing on every write would hold up everyone waiting on the app state
+    fn save(&self, job: &Job) -> anyhow::Result<()> {
+        self.db
+            .insert(job.id.as_bytes(), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    fn delete(&self, id: &Uuid) -> anyhow::Result<()> {
+        self.db.remove(id.as_bytes())?;
+        Ok(())
+    }
+
+    fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// Picks the store backend from `VERTD_JOB_STORE` ("sled" or "memory", defaults to "sled").
+/// The sled database lives at `VERTD_JOB_STORE_PATH` (defaults to "jobs").
+pub fn from_env() -> anyhow::Result<Box<dyn JobStore>> {
+    let backend = env::var("VERTD_JOB_STORE").unwrap_or_else(|_| "sled".to_string());
+    match backend.to_lowercase().as_str() {
+        "memory" => {
+            info!("using in-memory job store -- jobs will not survive a restart");
+            Ok(Box::new(MemoryStore))
+        }
+        "sled" => {
+            let path = env::var("VERTD_JOB_STORE_PATH").unwrap_or_else(|_| "jobs".to_string());
+            info!("using sled job store at {}", path);
+            Ok(Box::new(SledStore::open(&path)?))
+        }
+        _ => anyhow::bail!(
+            "invalid VERTD_JOB_STORE '{}' (expected 'sled' or 'memory')",
+            backend
+        ),
+    }
+}